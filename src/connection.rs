@@ -0,0 +1,154 @@
+use std::{io, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use hyper::client::HttpConnector;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::{service_fn, ServiceExt};
+use tracing::{debug, warn};
+
+/// Settings for connecting to the gRPC services
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectConfig {
+    /// How long a single connection attempt can take
+    pub connect_timeout: Duration,
+    /// Delay after the first failed attempt. Each next failure doubles it
+    pub backoff_base: Duration,
+    /// Upper limit for the delay
+    pub backoff_max: Duration,
+}
+
+#[derive(Debug, Default)]
+struct BackoffState {
+    failures: u32,
+    next_attempt: Option<Instant>,
+}
+
+impl BackoffState {
+    fn can_attempt(&self, now: Instant) -> bool {
+        self.next_attempt.is_none_or(|next_attempt| now >= next_attempt)
+    }
+
+    /// Returns the delay before the next attempt
+    fn failed(&mut self, config: &ConnectConfig, now: Instant) -> Duration {
+        let delay = config.backoff_base
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(config.backoff_max);
+
+        self.failures += 1;
+        self.next_attempt = Some(now + delay);
+        delay
+    }
+
+    /// Returns how many attempts have failed before this one
+    fn succeeded(&mut self) -> u32 {
+        std::mem::take(self).failures
+    }
+}
+
+/// Creates a channel that doesn't connect until the first request, so that the gateway can start before the services it depends on.
+/// Once the connection is lost, the channel reconnects on the next request. After a failed attempt, requests fail right away
+/// until the backoff delay passes, instead of each of them trying to connect to a service that is most likely still down
+pub fn new_lazy_channel(url: String, config: ConnectConfig) -> anyhow::Result<Channel> {
+    let endpoint = Endpoint::from_shared(url.clone())?.connect_timeout(config.connect_timeout);
+
+    // tonic is built without TLS, so an https url would end up speaking plain HTTP/2 to a TLS port
+    if endpoint.uri().scheme_str() == Some("https") {
+        anyhow::bail!("Could not use {url}, since connecting to the services over TLS is not supported");
+    }
+
+    // the same connector that tonic uses by default, which gets the port from the scheme and handles IPv6 hosts
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_nodelay(true);
+    http.set_connect_timeout(Some(config.connect_timeout));
+
+    let backoff = Arc::new(Mutex::new(BackoffState::default()));
+
+    let connector = service_fn(move |uri: Uri| {
+        let backoff = backoff.clone();
+        let url = url.clone();
+        let http = http.clone();
+
+        async move {
+            if !backoff.lock().unwrap().can_attempt(Instant::now()) {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("Waiting before reconnecting to {url}")));
+            }
+
+            let result = http.oneshot(uri).await.map_err(io::Error::other);
+
+            let mut backoff = backoff.lock().unwrap();
+
+            match &result {
+                Ok(_) => {
+                    let failures = backoff.succeeded();
+                    if failures > 0 {
+                        debug!("Reconnected to {url} after {failures} failed attempts");
+                    }
+                },
+                Err(e) => {
+                    let delay = backoff.failed(&config, Instant::now());
+                    warn!("Could not connect to {url} (attempt {}), retrying in {delay:?}: {e}", backoff.failures);
+                },
+            }
+
+            result
+        }
+    });
+
+    Ok(endpoint.connect_with_connector_lazy(connector))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: ConnectConfig = ConnectConfig {
+        connect_timeout: Duration::from_millis(100),
+        backoff_base: Duration::from_millis(100),
+        backoff_max: Duration::from_secs(1),
+    };
+
+    #[test]
+    fn fails_fast_during_delay() {
+        let mut backoff = BackoffState::default();
+        let now = Instant::now();
+        assert!(backoff.can_attempt(now));
+
+        let delay = backoff.failed(&CONFIG, now);
+        assert_eq!(CONFIG.backoff_base, delay);
+        assert!(!backoff.can_attempt(now));
+        assert!(!backoff.can_attempt(now + delay - Duration::from_millis(1)));
+        assert!(backoff.can_attempt(now + delay));
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let mut backoff = BackoffState::default();
+        let now = Instant::now();
+
+        let delays: Vec<_> = (0..6).map(|_| backoff.failed(&CONFIG, now).as_millis()).collect();
+        assert_eq!(vec![100, 200, 400, 800, 1000, 1000], delays);
+
+        // the exponent saturates instead of overflowing
+        backoff.failures = u32::MAX - 1;
+        assert_eq!(CONFIG.backoff_max, backoff.failed(&CONFIG, now));
+    }
+
+    #[test]
+    fn success_resets() {
+        let mut backoff = BackoffState::default();
+        let now = Instant::now();
+
+        backoff.failed(&CONFIG, now);
+        backoff.failed(&CONFIG, now);
+        assert_eq!(2, backoff.succeeded());
+
+        assert!(backoff.can_attempt(now));
+        assert_eq!(CONFIG.backoff_base, backoff.failed(&CONFIG, now));
+    }
+
+    #[tokio::test]
+    async fn https_rejected() {
+        assert!(new_lazy_channel("http://127.0.0.1:4040".into(), CONFIG).is_ok());
+        assert!(new_lazy_channel("https://127.0.0.1:4040".into(), CONFIG).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use axum::{extract::{Request, State}, http::Method, middleware::Next, response::Response};
+use axum_extra::extract::CookieJar;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+use crate::{error::ResError, types::{AppState, TokenSource}};
+
+const CSRF_TOKEN_LEN: usize = 32;
+
+/// Generates a new csrf token. Logging in and registering always get a new one, so that a token planted before the session started can't be used
+pub fn new_csrf_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CSRF_TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// Returns the csrf token that the client already has, or generates a new one.
+/// Reusing the old token makes sure that refreshing the session in one tab doesn't break requests in another
+pub fn get_or_new_csrf_token(jar: &CookieJar, state: &AppState) -> String {
+    match jar.get(&state.csrf_cookie_key) {
+        Some(c) if c.value().len() == CSRF_TOKEN_LEN => c.value().into(),
+        _ => new_csrf_token(),
+    }
+}
+
+/// Double-submit csrf check for mutating requests. The value of the csrf cookie has to be duplicated in the csrf header,
+/// which a cross-site request can't do since it can't read the cookie.
+/// Has to be layered after `auth_middleware`, because requests authenticated with a bearer token are exempt
+pub async fn csrf_middleware(
+    jar: CookieJar,
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ResError> {
+    let is_safe_method = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let is_bearer = req.extensions().get::<TokenSource>() == Some(&TokenSource::Bearer);
+
+    if is_safe_method || is_bearer {
+        return Ok(next.run(req).await);
+    }
+
+    let cookie_token = jar.get(&state.csrf_cookie_key).map(|c| c.value());
+    let header_token = req.headers()
+        .get(&state.csrf_header)
+        .and_then(|v| v.to_str().ok());
+
+    match (cookie_token, header_token) {
+        (Some(c), Some(h)) if !c.is_empty() && constant_time_eq(c.as_bytes(), h.as_bytes()) => Ok(next.run(req).await),
+        (None, _) => Err(ResError::Forbidden("Could not get the csrf token from the cookie jar".into())),
+        (_, None) => Err(ResError::Forbidden("Could not get the csrf token from the header".into())),
+        _ => Err(ResError::Forbidden("The csrf tokens in the cookie and the header do not match".into())),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -19,10 +19,20 @@ pub enum ResError {
     Unauthorized(String),
     /// When the request is authenticated but not allowed to access a resource
     Forbidden(String),
+    /// When the request conflicts with the current state of a resource
+    Conflict(String),
+    /// When the resource has changed since the version in the request's `If-Match` header
+    PreconditionFailed(String),
     /// When the issue with the request is too hard to explain
     BadRequest(String),
+    /// When the virus scanner has found something in an uploaded file
+    Infected(String),
 
     NotImplemented(String),
+    /// When the upload doesn't fit into the user's storage quota
+    QuotaExceeded(String),
+    /// When the service is shutting down or can't reach the services it depends on
+    ServiceUnavailable(String),
     /// Any error that is the service's fault
     ServerError(String),
 }
@@ -35,20 +45,33 @@ impl core::fmt::Display for ResError {
 
 impl std::error::Error for ResError {}
 
+impl ResError {
+    /// Splits the error into the status code, the message that the client gets to see, and the internal message
+    pub fn into_parts(self) -> (StatusCode, &'static str, String) {
+        match self {
+            Self::InvalidFields(m) => (StatusCode::BAD_REQUEST, "invalid fields", m),
+            Self::InvalidValues(m) => (StatusCode::UNPROCESSABLE_ENTITY, "invalid values", m),
+            Self::InvalidContentType(m) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid content type", m),
+            Self::NotFound(m) => (StatusCode::NOT_FOUND, "not found", m),
+            Self::Unauthorized(m) => (StatusCode::UNAUTHORIZED, "unauthorized", m),
+            Self::Forbidden(m) => (StatusCode::FORBIDDEN, "forbidden", m),
+            Self::Conflict(m) => (StatusCode::CONFLICT, "conflict", m),
+            Self::PreconditionFailed(m) => (StatusCode::PRECONDITION_FAILED, "precondition failed", m),
+            Self::BadRequest(m) => (StatusCode::BAD_REQUEST, "bad request", m),
+            Self::Infected(m) => (StatusCode::UNPROCESSABLE_ENTITY, "file is infected", m),
+
+            Self::NotImplemented(m) => (StatusCode::NOT_IMPLEMENTED, "not implemented", m),
+            Self::QuotaExceeded(m) => (StatusCode::INSUFFICIENT_STORAGE, "quota exceeded", m),
+            Self::ServiceUnavailable(m) => (StatusCode::SERVICE_UNAVAILABLE, "service unavailable", m),
+            Self::ServerError(m) => (StatusCode::INTERNAL_SERVER_ERROR, "server error", m),
+        }
+    }
+}
+
 impl IntoResponse for ResError {
     fn into_response(self) -> Response {
-        match self {
-            Self::InvalidFields(m) => new_err_res(StatusCode::BAD_REQUEST, "invalid fields", m),
-            Self::InvalidValues(m) => new_err_res(StatusCode::UNPROCESSABLE_ENTITY, "invalid values", m),
-            Self::InvalidContentType(m) => new_err_res(StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid content type", m),
-            Self::NotFound(m) => new_err_res(StatusCode::NOT_FOUND, "not found", m),
-            Self::Unauthorized(m) => new_err_res(StatusCode::UNAUTHORIZED, "unauthorized", m),
-            Self::Forbidden(m) => new_err_res(StatusCode::FORBIDDEN, "forbidden", m),
-            Self::BadRequest(m) => new_err_res(StatusCode::BAD_REQUEST, "bad request", m),
-
-            Self::NotImplemented(m) => new_err_res(StatusCode::NOT_IMPLEMENTED, "not implemented", m),
-            Self::ServerError(m) => new_err_res(StatusCode::INTERNAL_SERVER_ERROR, "server error", m),
-        }.into_response()
+        let (status_code, response_msg, internal_msg) = self.into_parts();
+        new_err_res(status_code, response_msg, internal_msg).into_response()
     }
 }
 
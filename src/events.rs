@@ -0,0 +1,207 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::proto::files::{create_file_metadata::AttachId, File};
+use crate::proto::{notes::Note, shelves::Shelf, tags::Tag};
+use crate::token_cache::{token_hash, TokenHash};
+
+/// How many of the latest events are kept for the clients that reconnect
+const REPLAY_SIZE: usize = 100;
+/// How long the events of a user without any connections are kept for
+const REPLAY_WINDOW: Duration = Duration::from_secs(300);
+/// How often the users without any connections get removed. Their events can be kept for up to twice the `REPLAY_WINDOW`
+const SWEEP_INTERVAL: Duration = REPLAY_WINDOW;
+/// How many events a connection can fall behind before it has to resync
+const CHANNEL_SIZE: usize = 64;
+/// How many logouts a connection can fall behind before it has to be closed, since it can't tell whether its own token was among them
+const LOGOUT_CHANNEL_SIZE: usize = 64;
+
+/// Change to the user's data that has been made through the gateway
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum ChangeEvent {
+    NoteCreated(Note),
+    NoteUpdated(Note),
+    NoteDeleted { id: i32 },
+    NoteTagAttached { note_id: i32, tag_id: i32 },
+    NoteTagDetached { note_id: i32, tag_id: i32 },
+    /// Notes have changed in a way that a single event can't describe, so they should be fetched again
+    NotesChanged,
+    TagCreated(Tag),
+    TagUpdated(Tag),
+    TagDeleted { id: i32 },
+    FileCreated { file: File, note_id: Option<i32>, shelf_id: Option<i32> },
+    FileDeleted { id: i32 },
+    ShelfUpdated(Shelf),
+}
+
+impl ChangeEvent {
+    pub fn file_created(file: File, attach_id: AttachId) -> Self {
+        match attach_id {
+            AttachId::NoteId(note_id) => Self::FileCreated { file, note_id: Some(note_id), shelf_id: None },
+            AttachId::ShelfId(shelf_id) => Self::FileCreated { file, note_id: None, shelf_id: Some(shelf_id) },
+        }
+    }
+}
+
+/// Event along with its sequence number, which is unique within the user's epoch
+#[derive(Debug, Serialize)]
+pub struct Envelope {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: ChangeEvent,
+}
+
+#[derive(Debug)]
+struct UserEvents {
+    tx: broadcast::Sender<Arc<Envelope>>,
+    /// Changes whenever the user's events are recreated, which tells the reconnecting clients that their sequence numbers mean nothing anymore
+    epoch: String,
+    next_seq: u64,
+    recent: VecDeque<Arc<Envelope>>,
+    last_event: Instant,
+}
+
+/// New connection's view of the user's events
+#[derive(Debug)]
+pub struct Subscription {
+    pub rx: broadcast::Receiver<Arc<Envelope>>,
+    pub epoch: String,
+    /// Sequence number of the latest event, or 0 if there are none
+    pub seq: u64,
+    /// Events that the client has missed since the sequence number it reconnected with.
+    /// `None` if they are no longer available, in which case the client has to fetch everything again
+    pub missed: Option<Vec<Arc<Envelope>>>,
+}
+
+/// In-process fan-out of change events to each user's open connections.
+/// Only changes made through this gateway instance are seen, so with several instances each one only knows about its own requests
+#[derive(Debug)]
+pub struct EventHub {
+    users: Mutex<HashMap<i32, UserEvents>>,
+    /// Hashes of the access tokens that have been logged out, so that the connections opened with them get closed
+    logouts: broadcast::Sender<TokenHash>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        Self {
+            users: Default::default(),
+            logouts: broadcast::channel(LOGOUT_CHANNEL_SIZE).0,
+        }
+    }
+}
+
+impl EventHub {
+    /// Creates the hub along with a task that removes the events of inactive users every `SWEEP_INTERVAL`, until the hub gets dropped
+    pub fn start() -> Arc<Self> {
+        let hub = Arc::new(Self::default());
+        let weak = Arc::downgrade(&hub);
+
+        tokio::spawn(async move {
+            let mut sweep = tokio::time::interval_at(tokio::time::Instant::now() + SWEEP_INTERVAL, SWEEP_INTERVAL);
+
+            loop {
+                sweep.tick().await;
+
+                let Some(hub) = weak.upgrade() else {
+                    return;
+                };
+
+                hub.remove_inactive();
+            }
+        });
+
+        hub
+    }
+
+    fn remove_inactive(&self) {
+        self.users.lock().unwrap()
+            .retain(|_, events| events.tx.receiver_count() > 0 || events.last_event.elapsed() < REPLAY_WINDOW);
+    }
+
+    fn user_events(users: &mut HashMap<i32, UserEvents>, user_id: i32) -> &mut UserEvents {
+        users.entry(user_id).or_insert_with(|| UserEvents {
+            tx: broadcast::channel(CHANNEL_SIZE).0,
+            epoch: thread_rng().sample_iter(&Alphanumeric).take(8).map(char::from).collect(),
+            next_seq: 1,
+            recent: VecDeque::with_capacity(REPLAY_SIZE),
+            last_event: Instant::now(),
+        })
+    }
+
+    pub fn publish(&self, user_id: i32, event: ChangeEvent) {
+        let mut users = self.users.lock().unwrap();
+
+        let events = Self::user_events(&mut users, user_id);
+        let envelope = Arc::new(Envelope { seq: events.next_seq, event });
+
+        events.next_seq += 1;
+        events.last_event = Instant::now();
+
+        if events.recent.len() == REPLAY_SIZE {
+            events.recent.pop_front();
+        }
+
+        events.recent.push_back(envelope.clone());
+
+        // there might be nobody listening, which is fine
+        let _ = events.tx.send(envelope);
+    }
+
+    /// `since` is the epoch and the sequence number of the last event that a reconnecting client has received
+    pub fn subscribe(&self, user_id: i32, since: Option<(&str, u64)>) -> Subscription {
+        let mut users = self.users.lock().unwrap();
+
+        let events = Self::user_events(&mut users, user_id);
+        let seq = events.next_seq - 1;
+
+        let missed = match since {
+            None => Some(Vec::new()),
+            Some((epoch, since)) if epoch != events.epoch || since > seq => None,
+            Some((_, since)) => match events.recent.front() {
+                Some(oldest) if oldest.seq > since + 1 => None,
+                _ => Some(events.recent.iter().filter(|e| e.seq > since).cloned().collect()),
+            },
+        };
+
+        Subscription { rx: events.tx.subscribe(), epoch: events.epoch.clone(), seq, missed }
+    }
+
+    /// Closes the connections that have been opened with the access token
+    pub fn logout(&self, access_token: &str) {
+        // there might be nobody listening, which is fine
+        let _ = self.logouts.send(token_hash(access_token));
+    }
+
+    pub fn subscribe_logouts(&self) -> broadcast::Receiver<TokenHash> {
+        self.logouts.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn inactive_users_removed() {
+        let hub = EventHub::start();
+
+        hub.publish(1, ChangeEvent::NotesChanged);
+        hub.publish(2, ChangeEvent::NotesChanged);
+        let _subscription = hub.subscribe(2, None);
+
+        for events in hub.users.lock().unwrap().values_mut() {
+            events.last_event -= REPLAY_WINDOW;
+        }
+
+        hub.remove_inactive();
+
+        let users = hub.users.lock().unwrap();
+        assert!(!users.contains_key(&1));
+        assert!(users.contains_key(&2));
+    }
+}
@@ -0,0 +1,97 @@
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, str::FromStr};
+
+use axum::{async_trait, extract::{ConnectInfo, FromRequestParts}, http::{header, request::Parts}};
+use sha2::{Digest, Sha256};
+
+use crate::{error::ResError, types::AppState};
+
+/// Max length of the fingerprint that the client is allowed to send in the header
+const MAX_CLIENT_FINGERPRINT_LEN: usize = 256;
+
+/// Defines how much of the client's ip address goes into the fingerprint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpMode {
+    /// The ip doesn't affect the fingerprint
+    None,
+    /// The whole ip address is used
+    Full,
+    /// Only the /24 (IPv4) or /64 (IPv6) subnet is used, so that clients with dynamic addresses don't get logged out all the time
+    Subnet,
+}
+
+impl FromStr for IpMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "full" => Ok(Self::Full),
+            "subnet" => Ok(Self::Subnet),
+            _ => Err(anyhow::anyhow!("Invalid fingerprint ip mode: {s}, expected one of: none, full, subnet")),
+        }
+    }
+}
+
+/// Extractor for the client's fingerprint that gets passed to the Auth service to bind refresh tokens to a specific client.
+/// The fingerprint is a hash of the value from the configured fingerprint header, the User-Agent header and the client's ip address (depending on `IpMode`)
+#[derive(Debug)]
+pub struct Fingerprint(pub String);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Fingerprint {
+    type Rejection = ResError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let client_value = header_str(parts, &state.fingerprint_header);
+        if client_value.len() > MAX_CLIENT_FINGERPRINT_LEN {
+            return Err(ResError::InvalidFields(format!("The fingerprint header is longer than {MAX_CLIENT_FINGERPRINT_LEN} bytes")));
+        }
+
+        let user_agent = header_str(parts, &header::USER_AGENT);
+
+        let ip = parts.extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .and_then(|ConnectInfo(addr)| mask_ip(addr.ip(), state.fingerprint_ip_mode))
+            .map(|ip| ip.to_string())
+            .unwrap_or_default();
+
+        Ok(Self(new_fingerprint(client_value, user_agent, &ip)))
+    }
+}
+
+fn header_str<'a>(parts: &'a Parts, key: &header::HeaderName) -> &'a str {
+    parts.headers
+        .get(key)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+}
+
+/// Hashes the fingerprint parts together, so that neither of them get sent to the Auth service as is
+fn new_fingerprint(client_value: &str, user_agent: &str, ip: &str) -> String {
+    let mut hasher = Sha256::new();
+
+    for part in [client_value, user_agent, ip] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+fn mask_ip(ip: IpAddr, mode: IpMode) -> Option<IpAddr> {
+    // the service listens on [::], so IPv4 clients show up as IPv4-mapped IPv6 addresses
+    let ip = ip.to_canonical();
+
+    match (mode, ip) {
+        (IpMode::None, _) => None,
+        (IpMode::Full, ip) => Some(ip),
+        (IpMode::Subnet, IpAddr::V4(v4)) => {
+            let [a, b, c, _] = v4.octets();
+            Some(IpAddr::V4(Ipv4Addr::new(a, b, c, 0)))
+        },
+        (IpMode::Subnet, IpAddr::V6(v6)) => {
+            let [a, b, c, d, ..] = v6.segments();
+            Some(IpAddr::V6(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0)))
+        },
+    }
+}
@@ -0,0 +1,467 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use async_stream::stream;
+use axum::body::{Body, BodyDataStream, Bytes, HttpBody};
+use axum::extract::{OriginalUri, Request, State};
+use axum::http::{header::CONTENT_TYPE, request::Parts, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::{middleware::Next, response::{IntoResponse, Response}};
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+
+use crate::{error::ResError, types::AppState};
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// Set on the responses that have been replayed instead of handling the request again
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+const MAX_KEY_LEN: usize = 255;
+/// Responses bigger than this don't get stored, so their repeats get handled as new requests
+const MAX_STORED_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+/// Multipart delimiters get hashed as this instead, so that the boundary chosen by the client doesn't matter
+const DELIMITER_MARKER: &[u8] = b"\0--\0";
+
+/// Hashes of what the request was sent to and of its body, along with the body's length,
+/// so that repeats with a different body can be turned away without reading all of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestHash {
+    target: [u8; 32],
+    body: [u8; 32],
+    body_len: u64,
+}
+
+/// User id and idempotency key, since the keys are only unique for a single user
+type StoreKey = (i32, String);
+
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl IntoResponse for StoredResponse {
+    fn into_response(self) -> Response {
+        let mut res = (self.status, self.headers, self.body).into_response();
+        res.headers_mut().insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+        res
+    }
+}
+
+#[derive(Debug)]
+enum Entry {
+    /// The first request with the key is still being handled
+    Pending,
+    Done(RequestHash, Box<StoredResponse>),
+}
+
+/// What should be done with a request that came with an idempotency key
+#[derive(Debug)]
+pub enum Claim {
+    /// The key hasn't been used yet, so the request should be handled and its response stored with the reservation
+    New(Reservation),
+    InProgress,
+    Done(RequestHash, Box<StoredResponse>),
+}
+
+/// Bounded in-memory store of the responses to the requests that came with an idempotency key.
+/// Same as with `TokenCache`, all entries live for the same amount of time, so the insertion order is also the expiration order
+#[derive(Debug)]
+pub struct IdempotencyStore {
+    ttl: Duration,
+    max_size: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<StoreKey, (Entry, Instant)>,
+    queue: VecDeque<(StoreKey, Instant)>,
+}
+
+impl Inner {
+    /// Gets rid of expired entries first, and then of the oldest ones if the store is still full
+    fn make_room(&mut self, max_size: usize, now: Instant) {
+        while let Some((_, expires_at)) = self.queue.front() {
+            if *expires_at > now && self.entries.len() < max_size {
+                break;
+            }
+
+            let (key, expires_at) = self.queue.pop_front().unwrap();
+            if self.entries.get(&key).is_some_and(|(_, e)| *e == expires_at) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+impl IdempotencyStore {
+    /// `ttl` is in seconds. A ttl or size of 0 disables the store, and the keys get ignored
+    pub fn new(ttl: u64, max_size: usize) -> Self {
+        Self {
+            ttl: Duration::from_secs(ttl),
+            max_size,
+            inner: Default::default(),
+        }
+    }
+
+    fn is_disabled(&self) -> bool {
+        self.ttl.is_zero() || self.max_size == 0
+    }
+
+    pub fn claim(self: &Arc<Self>, user_id: i32, key: String) -> Claim {
+        if self.is_disabled() {
+            return Claim::New(Reservation { inner: None });
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let key = (user_id, key);
+
+        match inner.entries.get(&key) {
+            Some((Entry::Pending, expires_at)) if *expires_at > now => return Claim::InProgress,
+            Some((Entry::Done(hash, res), expires_at)) if *expires_at > now => return Claim::Done(*hash, res.clone()),
+            _ => (),
+        }
+
+        inner.make_room(self.max_size, now);
+
+        let expires_at = now + self.ttl;
+        inner.entries.insert(key.clone(), (Entry::Pending, expires_at));
+        inner.queue.push_back((key.clone(), expires_at));
+
+        Claim::New(Reservation { inner: Some((self.clone(), key)) })
+    }
+
+    fn complete(&self, key: StoreKey, hash: RequestHash, response: StoredResponse) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        // the pending entry might have been pushed out in the meantime
+        inner.make_room(self.max_size, now);
+
+        let expires_at = now + self.ttl;
+        inner.entries.insert(key.clone(), (Entry::Done(hash, Box::new(response)), expires_at));
+        inner.queue.push_back((key, expires_at));
+    }
+
+    fn release(&self, key: &StoreKey) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if matches!(inner.entries.get(key), Some((Entry::Pending, _))) {
+            inner.entries.remove(key);
+        }
+    }
+}
+
+/// A claimed idempotency key. If it gets dropped without storing a response, the key is released,
+/// so that the request can be retried
+#[derive(Debug)]
+pub struct Reservation {
+    inner: Option<(Arc<IdempotencyStore>, StoreKey)>,
+}
+
+impl Reservation {
+    fn complete(mut self, hash: RequestHash, response: StoredResponse) {
+        if let Some((store, key)) = self.inner.take() {
+            store.complete(key, hash, response);
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some((store, key)) = self.inner.take() {
+            store.release(&key);
+        }
+    }
+}
+
+/// Makes `POST` requests with the `Idempotency-Key` header safe to retry. The first response to each key gets stored
+/// and replayed for the repeats, unless it's a server error. Reusing a key for a different request (method, path, query or body)
+/// results in 409, and so does repeating a request while the first one is still being handled.
+/// Multipart bodies are compared without their boundaries, since clients usually pick a new one for every request.
+/// Has to be layered after `auth_middleware`, because the keys are per user
+pub async fn idempotency_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ResError> {
+    let (Some(key), Some(&user_id)) = (req.headers().get(&IDEMPOTENCY_KEY), req.extensions().get::<i32>()) else {
+        return Ok(next.run(req).await);
+    };
+
+    let key = match key.to_str() {
+        Ok(k) if !k.is_empty() && k.len() <= MAX_KEY_LEN => k.to_string(),
+        _ => return Err(ResError::InvalidFields(format!("Received an invalid idempotency key: {key:?}"))),
+    };
+
+    let (parts, body) = req.into_parts();
+    let target = request_target(&parts);
+    let hasher = BodyHasher::new(&parts.headers);
+
+    match state.idempotency.claim(user_id, key.clone()) {
+        Claim::InProgress => Err(ResError::Conflict(format!("A request with the idempotency key {key} is still in progress"))),
+        Claim::Done(stored_hash, response) => {
+            let reused = || ResError::Conflict(format!("The idempotency key {key} has already been used for a different request"));
+
+            if target != stored_hash.target {
+                return Err(reused());
+            }
+
+            // the body has to be read to tell whether it's the same request,
+            // but there is no need to read past the length of the original one
+
+            let mut data = body.into_data_stream();
+            let mut hasher = hasher;
+
+            while let Some(chunk) = data.next().await {
+                let chunk = chunk.map_err(|e| ResError::BadRequest(format!("Could not read the request body: {e}")))?;
+                hasher.update(&chunk);
+
+                if hasher.len > stored_hash.body_len {
+                    return Err(reused());
+                }
+            }
+
+            match hasher.finish(target) == stored_hash {
+                true => Ok(response.into_response()),
+                false => Err(reused()),
+            }
+        },
+        Claim::New(reservation) => {
+            let (body, hashing) = hashing_body(hasher, body);
+            let response = next.run(Request::from_parts(parts, body)).await;
+
+            if response.status().is_server_error() || response.body().size_hint().upper().is_none_or(|s| s > MAX_STORED_RESPONSE_SIZE) {
+                return Ok(response);
+            }
+
+            // the handler might not have read the whole body, for example the epilogue after the closing multipart boundary.
+            // if the body failed, there is nothing to compare the repeats with
+            let Some(hash) = finish_hash(&hashing, target).await else {
+                return Ok(response);
+            };
+
+            let (res_parts, res_body) = response.into_parts();
+            let res_body = axum::body::to_bytes(res_body, MAX_STORED_RESPONSE_SIZE as usize).await
+                .map_err(|e| ResError::ServerError(format!("Could not read the response body: {e}")))?;
+
+            reservation.complete(hash, StoredResponse {
+                status: res_parts.status,
+                headers: res_parts.headers.clone(),
+                body: res_body.clone(),
+            });
+
+            Ok(Response::from_parts(res_parts, Body::from(res_body)))
+        },
+    }
+}
+
+/// Hashes the request's method and its full uri, so that a key can't be reused for a different route
+fn request_target(parts: &Parts) -> [u8; 32] {
+    let uri = parts.extensions.get::<OriginalUri>().map_or(&parts.uri, |u| &u.0);
+
+    let mut hasher = Sha256::new();
+    hasher.update(parts.method.as_str());
+    hasher.update([0]);
+    hasher.update(uri.to_string());
+    hasher.finalize().into()
+}
+
+/// Gets the boundary out of a `multipart/*` content type
+fn multipart_boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let (mime, params) = content_type.split_once(';')?;
+
+    if !mime.trim().to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+
+    params.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        let value = value.trim().trim_matches('"');
+
+        match name.trim().eq_ignore_ascii_case("boundary") && !value.is_empty() {
+            true => Some(value.to_string()),
+            false => None,
+        }
+    })
+}
+
+/// Hashes the body as it comes. For multipart bodies, each delimiter gets replaced with `DELIMITER_MARKER`,
+/// and the end of what has been received is held back until it can't be the start of a delimiter anymore
+struct BodyHasher {
+    hasher: Sha256,
+    delimiter: Option<Vec<u8>>,
+    held: Vec<u8>,
+    /// How much has been hashed so far
+    len: u64,
+}
+
+impl BodyHasher {
+    fn new(headers: &HeaderMap) -> Self {
+        Self {
+            hasher: Sha256::new(),
+            delimiter: multipart_boundary(headers).map(|b| format!("--{b}").into_bytes()),
+            held: Vec::new(),
+            len: 0,
+        }
+    }
+
+    fn hash(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+        self.len += bytes.len() as u64;
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        let Some(delimiter) = self.delimiter.take() else {
+            self.hash(chunk);
+            return;
+        };
+
+        let mut held = std::mem::take(&mut self.held);
+        held.extend_from_slice(chunk);
+
+        let mut start = 0;
+
+        while let Some(pos) = held[start..].windows(delimiter.len()).position(|w| w == delimiter) {
+            self.hash(&held[start..start + pos]);
+            self.hash(DELIMITER_MARKER);
+            start += pos + delimiter.len();
+        }
+
+        let keep_from = held.len().saturating_sub(delimiter.len() - 1).max(start);
+        self.hash(&held[start..keep_from]);
+        held.drain(..keep_from);
+
+        self.held = held;
+        self.delimiter = Some(delimiter);
+    }
+
+    fn finish(mut self, target: [u8; 32]) -> RequestHash {
+        let held = std::mem::take(&mut self.held);
+        self.hash(&held);
+
+        RequestHash { target, body: self.hasher.finalize().into(), body_len: self.len }
+    }
+}
+
+/// Rest of the request body along with the hash of what has been read so far
+struct HashingState {
+    data: BodyDataStream,
+    hasher: Option<BodyHasher>,
+    failed: bool,
+}
+
+/// Wraps the body so that it gets hashed while the handler reads it. The state is shared, so that the rest of the body
+/// can be read and hashed once the handler is done with it
+fn hashing_body(hasher: BodyHasher, body: Body) -> (Body, Arc<tokio::sync::Mutex<HashingState>>) {
+    let state = Arc::new(tokio::sync::Mutex::new(HashingState { data: body.into_data_stream(), hasher: Some(hasher), failed: false }));
+    let shared = state.clone();
+
+    let stream = stream! {
+        loop {
+            let chunk = {
+                let mut state = state.lock().await;
+                let chunk = state.data.next().await;
+
+                match &chunk {
+                    Some(Ok(bytes)) => if let Some(hasher) = &mut state.hasher {
+                        hasher.update(bytes);
+                    },
+                    Some(Err(_)) => state.failed = true,
+                    None => (),
+                }
+
+                chunk
+            };
+
+            let Some(chunk) = chunk else {
+                return;
+            };
+
+            let failed = chunk.is_err();
+            yield chunk;
+
+            if failed {
+                return;
+            }
+        }
+    };
+
+    (Body::from_stream(stream), shared)
+}
+
+/// Reads whatever the handler has left of the body, and returns the hash of the whole request. Returns `None` if the body has failed
+async fn finish_hash(state: &tokio::sync::Mutex<HashingState>, target: [u8; 32]) -> Option<RequestHash> {
+    let mut state = state.lock().await;
+
+    while !state.failed {
+        match state.data.next().await {
+            Some(Ok(bytes)) => state.hasher.as_mut()?.update(&bytes),
+            Some(Err(_)) => state.failed = true,
+            None => return Some(state.hasher.take()?.finish(target)),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn multipart(boundary: &str, content: &str) -> (HeaderMap, String) {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, format!("multipart/form-data; boundary=\"{boundary}\"").parse().unwrap());
+
+        let body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"note_id\"\r\n\r\n1\r\n\
+            --{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\n{content}\r\n\
+            --{boundary}--\r\n",
+        );
+
+        (headers, body)
+    }
+
+    fn hash_chunks(headers: &HeaderMap, body: &str, chunk_size: usize) -> RequestHash {
+        let mut hasher = BodyHasher::new(headers);
+
+        for chunk in body.as_bytes().chunks(chunk_size) {
+            hasher.update(chunk);
+        }
+
+        hasher.finish([0; 32])
+    }
+
+    #[test]
+    fn boundary_ignored() {
+        let (headers, body) = multipart("first", "content");
+        let (other_headers, other_body) = multipart("a-much-longer-boundary", "content");
+
+        let hash = hash_chunks(&headers, &body, body.len());
+
+        for chunk_size in [1, 3, 7, 64] {
+            assert_eq!(hash, hash_chunks(&headers, &body, chunk_size));
+            assert_eq!(hash, hash_chunks(&other_headers, &other_body, chunk_size));
+        }
+    }
+
+    #[test]
+    fn different_content_differs() {
+        let (headers, body) = multipart("first", "content");
+        let (other_headers, other_body) = multipart("second", "contents");
+
+        assert_ne!(hash_chunks(&headers, &body, 5), hash_chunks(&other_headers, &other_body, 5));
+    }
+
+    #[test]
+    fn other_bodies_hashed_as_is() {
+        let headers = HeaderMap::new();
+        let hash = hash_chunks(&headers, "--first\r\n", 2);
+
+        assert_ne!(hash, hash_chunks(&headers, "--second\r\n", 2));
+        assert_eq!(10, hash_chunks(&headers, "--second\r\n", 2).body_len);
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::error::ResError;
+use crate::types::unix_now;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a public download link grants access to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkClaims {
+    pub user_id: i32,
+    pub file_hash: String,
+    /// Unix timestamp (in seconds)
+    pub expires: u64,
+    pub max_downloads: Option<u32>,
+    /// Identifies the link for counting the downloads
+    pub link_id: String,
+}
+
+/// Signs and verifies public download links. Tokens look like `{key_id}.{base64 claims}.{base64 signature}`.
+/// The first key is used for signing and the rest are only used for verifying,
+/// so that keys can be rotated without breaking the links that are already out there
+#[derive(Debug)]
+pub struct LinkSigner {
+    keys: Vec<(String, Vec<u8>)>,
+    /// Download counts of the links that have a limit, along with their expiry time
+    downloads: Mutex<HashMap<String, (u32, u64)>>,
+}
+
+impl std::str::FromStr for LinkSigner {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of `key_id:secret` pairs
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let keys = s
+            .split(',')
+            .map(|pair| match pair.trim().split_once(':') {
+                Some((id, secret)) if !id.is_empty() && !id.contains('.') && secret.len() >= 32 => {
+                    Ok((id.to_string(), secret.as_bytes().to_vec()))
+                },
+                _ => Err(anyhow::anyhow!("Invalid link signing key, expected key_id:secret with a secret of at least 32 characters")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { keys, downloads: Default::default() })
+    }
+}
+
+impl LinkSigner {
+    pub fn new_link_id() -> String {
+        thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect()
+    }
+
+    pub fn sign(&self, claims: &LinkClaims) -> Result<String, ResError> {
+        let (key_id, secret) = &self.keys[0];
+
+        let payload = serde_json::to_vec(claims).map_err(|e| ResError::ServerError(e.to_string()))?;
+        let payload = format!("{key_id}.{}", URL_SAFE_NO_PAD.encode(payload));
+
+        let mut mac = HmacSha256::new_from_slice(secret).map_err(|e| ResError::ServerError(e.to_string()))?;
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        Ok(format!("{payload}.{signature}"))
+    }
+
+    /// Checks the token's signature and expiry time
+    pub fn verify(&self, token: &str) -> Result<LinkClaims, ResError> {
+        let invalid = || ResError::Forbidden("The link is invalid".into());
+
+        let (payload, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let (key_id, claims) = payload.split_once('.').ok_or_else(invalid)?;
+        let (_, secret) = self.keys.iter().find(|(id, _)| id == key_id).ok_or_else(invalid)?;
+
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+
+        let mut mac = HmacSha256::new_from_slice(secret).map_err(|e| ResError::ServerError(e.to_string()))?;
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| invalid())?;
+
+        let claims = URL_SAFE_NO_PAD.decode(claims).map_err(|_| invalid())?;
+        let claims: LinkClaims = serde_json::from_slice(&claims).map_err(|_| invalid())?;
+
+        if claims.expires <= unix_now() {
+            return Err(ResError::Forbidden("The link has expired".into()));
+        }
+
+        Ok(claims)
+    }
+
+    /// Counts a download for links that have a limit. The counts are only kept in memory, so they reset when the service restarts
+    pub fn take_download(&self, claims: &LinkClaims) -> Result<(), ResError> {
+        let Some(max_downloads) = claims.max_downloads else {
+            return Ok(());
+        };
+
+        let mut downloads = self.downloads.lock().unwrap();
+
+        let now = unix_now();
+        downloads.retain(|_, (_, expires)| *expires > now);
+
+        let (count, _) = downloads.entry(claims.link_id.clone()).or_insert((0, claims.expires));
+
+        if *count >= max_downloads {
+            return Err(ResError::Forbidden("The link has reached its download limit".into()));
+        }
+
+        *count += 1;
+        Ok(())
+    }
+
+    /// Undoes `take_download` for requests that didn't end up downloading the file
+    pub fn give_back_download(&self, claims: &LinkClaims) {
+        if claims.max_downloads.is_none() {
+            return;
+        }
+
+        if let Some((count, _)) = self.downloads.lock().unwrap().get_mut(&claims.link_id) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+    const OLD_SECRET: &str = "fedcba9876543210fedcba9876543210";
+
+    fn signer(keys: &str) -> LinkSigner {
+        keys.parse().unwrap()
+    }
+
+    fn claims(expires: u64) -> LinkClaims {
+        LinkClaims { user_id: 1, file_hash: "hash".into(), expires, max_downloads: Some(2), link_id: LinkSigner::new_link_id() }
+    }
+
+    fn is_forbidden(result: Result<LinkClaims, ResError>) -> bool {
+        matches!(result, Err(ResError::Forbidden(_)))
+    }
+
+    #[test]
+    fn round_trip() {
+        let signer = signer(&format!("k1:{SECRET}"));
+        let token = signer.sign(&claims(unix_now() + 60)).unwrap();
+
+        assert!(token.starts_with("k1."));
+
+        let verified = signer.verify(&token).unwrap();
+        assert_eq!(1, verified.user_id);
+        assert_eq!("hash", verified.file_hash);
+        assert_eq!(Some(2), verified.max_downloads);
+    }
+
+    #[test]
+    fn tampered_payload_rejected() {
+        let signer = signer(&format!("k1:{SECRET}"));
+        let token = signer.sign(&claims(unix_now() + 60)).unwrap();
+
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+        let mut other = claims(unix_now() + 60);
+        other.user_id = 2;
+        let forged = format!("k1.{}.{signature}", URL_SAFE_NO_PAD.encode(serde_json::to_vec(&other).unwrap()));
+
+        assert_ne!(payload, forged.rsplit_once('.').unwrap().0);
+        assert!(is_forbidden(signer.verify(&forged)));
+        assert!(is_forbidden(signer.verify(&format!("{payload}.{}", URL_SAFE_NO_PAD.encode([0; 32])))));
+        assert!(is_forbidden(signer.verify("not a token")));
+    }
+
+    #[test]
+    fn unknown_key_rejected() {
+        let token = signer(&format!("k2:{SECRET}")).sign(&claims(unix_now() + 60)).unwrap();
+        assert!(is_forbidden(signer(&format!("k1:{SECRET}")).verify(&token)));
+    }
+
+    #[test]
+    fn expired_rejected() {
+        let signer = signer(&format!("k1:{SECRET}"));
+        let token = signer.sign(&claims(unix_now() - 1)).unwrap();
+
+        assert!(is_forbidden(signer.verify(&token)));
+    }
+
+    #[test]
+    fn rotated_key_still_verifies() {
+        let token = signer(&format!("old:{OLD_SECRET}")).sign(&claims(unix_now() + 60)).unwrap();
+
+        // the new key signs from now on, while the old one is only kept for verifying
+        let rotated = signer(&format!("new:{SECRET},old:{OLD_SECRET}"));
+        assert!(rotated.verify(&token).is_ok());
+        assert!(rotated.sign(&claims(unix_now() + 60)).unwrap().starts_with("new."));
+
+        // once the old key is gone, its links stop working
+        assert!(is_forbidden(signer(&format!("new:{SECRET}")).verify(&token)));
+    }
+
+    #[test]
+    fn downloads_limited() {
+        let signer = signer(&format!("k1:{SECRET}"));
+        let claims = claims(unix_now() + 60);
+
+        assert!(signer.take_download(&claims).is_ok());
+        assert!(signer.take_download(&claims).is_ok());
+        assert!(signer.take_download(&claims).is_err());
+
+        signer.give_back_download(&claims);
+        assert!(signer.take_download(&claims).is_ok());
+    }
+}
@@ -1,9 +1,27 @@
-use crate::types::AppState;
+use std::{future::IntoFuture, net::SocketAddr, sync::Arc, time::Duration};
+
+use tracing::{error, warn};
+
+use crate::{connection::ConnectConfig, events::EventHub, idempotency::IdempotencyStore, thumbnail_cache::ThumbnailCache, scanner::Scanner, token_cache::TokenCache, types::{cookie_path_matches, parse_same_site, AppState, CookieConfig}, upload_store::UploadStore};
 
 mod types;
 mod error;
+mod events;
+mod connection;
+mod csrf;
+mod fingerprint;
+mod idempotency;
+mod links;
+mod refresh;
+mod token_cache;
 mod proto;
 mod routes;
+mod scanner;
+mod shutdown;
+mod telemetry;
+mod thumbnail_cache;
+mod upload_progress;
+mod upload_store;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -13,20 +31,86 @@ async fn main() -> anyhow::Result<()> {
     let app = routes::get_router(&state)?;
     let listener = tokio::net::TcpListener::bind(&addr).await?;
 
+    // on shutdown, the server stops accepting connections and waits for the in-flight requests to finish.
+    // if they don't finish in time, the file streams get cancelled and the server gets dropped
+
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(shutdown.clone().wait_for_signal());
+
+    if let Some(metrics_port) = state.metrics_port {
+        let metrics_addr = format!("[::]:{metrics_port}");
+        let metrics_listener = tokio::net::TcpListener::bind(&metrics_addr).await?;
+        let metrics_server = axum::serve(metrics_listener, telemetry::get_router())
+            .with_graceful_shutdown(shutdown.clone().wait_for_start());
+
+        println!("Metrics listening on {metrics_addr}");
+
+        tokio::spawn(async move {
+            if let Err(e) = metrics_server.await {
+                error!("The metrics server has stopped: {e}");
+            }
+        });
+    }
+
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown.clone().wait_for_start())
+        .into_future();
+
     println!("Gateway service listening on {addr}\n");
-    axum::serve(listener, app).await?;
+
+    tokio::select! {
+        res = server => res?,
+        _ = shutdown.grace_period_over(Duration::from_secs(state.shutdown_grace_period)) => {
+            warn!("Shutdown grace period is over, cancelling the remaining file streams");
+            shutdown.cancel_streams();
+
+            // giving the cancelled streams a moment to send their errors
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        },
+    }
+
+    shutdown.log_in_flight();
 
     Ok(())
 }
 
 async fn load_state() -> anyhow::Result<AppState> {
     let file_chunk_size = dotenvy::var("MAX_FILE_CHUNK_SIZE")?.parse()?;
+    let access_token_ttl = dotenvy::var("ACCESS_TOKEN_TTL")?.parse()?;
+
+    let cookie_config = CookieConfig {
+        secure: dotenvy::var("COOKIE_SECURE")?.parse()?,
+        same_site: parse_same_site(&dotenvy::var("COOKIE_SAME_SITE")?)?,
+        domain: dotenvy::var("COOKIE_DOMAIN").ok().filter(|d| !d.is_empty()),
+        path: dotenvy::var("COOKIE_PATH")?,
+        refresh_path: dotenvy::var("REFRESH_COOKIE_PATH")?,
+        host_prefix: dotenvy::var("COOKIE_HOST_PREFIX")?.parse()?,
+    };
+
+    if cookie_config.host_prefix && (!cookie_config.secure || cookie_config.domain.is_some()) {
+        anyhow::bail!("Cookie prefixes require COOKIE_SECURE to be true and COOKIE_DOMAIN to be empty");
+    }
+
+    // the middleware can only refresh the tokens if the browsers send the refresh cookie along with the protected requests
+
+    let auto_refresh = dotenvy::var("AUTO_REFRESH")?.parse()?;
+
+    if auto_refresh && !routes::protected_paths().all(|p| cookie_path_matches(&cookie_config.refresh_path, p)) {
+        anyhow::bail!("AUTO_REFRESH requires REFRESH_COOKIE_PATH to cover all of the protected routes, otherwise the refresh cookie never reaches them");
+    }
+
+    let connect_config = ConnectConfig {
+        connect_timeout: Duration::from_millis(dotenvy::var("GRPC_CONNECT_TIMEOUT")?.parse()?),
+        backoff_base: Duration::from_millis(dotenvy::var("GRPC_BACKOFF_BASE")?.parse()?),
+        backoff_max: Duration::from_millis(dotenvy::var("GRPC_BACKOFF_MAX")?.parse()?),
+    };
 
     let rpc_clients = routes::get_rpc_clients(
         dotenvy::var("AUTH_URL")?,
         dotenvy::var("DATA_URL")?,
         file_chunk_size,
-    ).await?;
+        connect_config,
+    )?;
 
     Ok(AppState {
         log_level: dotenvy::var("LOG_LEVEL")?.parse()?,
@@ -34,19 +118,69 @@ async fn load_state() -> anyhow::Result<AppState> {
         frontend_url: dotenvy::var("FRONTEND_URL")?,
         req_body_limit: dotenvy::var("MAX_REQUEST_BODY_SIZE")?.parse()?,
         file_chunk_size,
+        upload_store: Arc::new(UploadStore::new(
+            dotenvy::var("UPLOAD_DIR")?.into(),
+            dotenvy::var("MAX_UPLOAD_SIZE")?.parse()?,
+            dotenvy::var("UPLOAD_EXPIRATION")?.parse()?,
+        )?),
+        upload_progress: Default::default(),
+        events: EventHub::start(),
+        idempotency: Arc::new(IdempotencyStore::new(
+            dotenvy::var("IDEMPOTENCY_TTL")?.parse()?,
+            dotenvy::var("IDEMPOTENCY_STORE_SIZE")?.parse()?,
+        )),
+        scanner: Arc::new(Scanner::new(
+            dotenvy::var("CLAMD_ADDR").ok().filter(|a| !a.is_empty()),
+            dotenvy::var("SCAN_POLICY")?.parse()?,
+            dotenvy::var("CLAMD_TIMEOUT")?.parse()?,
+        )),
+        user_storage_quota: dotenvy::var("USER_STORAGE_QUOTA")?.parse()?,
+        user_file_quota: dotenvy::var("USER_FILE_QUOTA")?.parse()?,
+        quota_tracker: Default::default(),
+        public_url: dotenvy::var("PUBLIC_URL")?,
+        link_signer: Arc::new(dotenvy::var("LINK_SIGNING_KEYS")?.parse()?),
+        max_link_ttl: dotenvy::var("MAX_LINK_TTL")?.parse()?,
+        thumbnail_cache: Arc::new(ThumbnailCache::new(
+            dotenvy::var("THUMBNAIL_DIR")?.into(),
+            dotenvy::var("MAX_THUMBNAIL_INPUT_SIZE")?.parse()?,
+            dotenvy::var("MAX_THUMBNAIL_CACHE_SIZE")?.parse()?,
+        )?),
 
         auth_token: dotenvy::var("AUTH_TOKEN")?,
         data_token: dotenvy::var("DATA_TOKEN")?,
 
-        access_token_ttl: dotenvy::var("ACCESS_TOKEN_TTL")?.parse()?,
+        access_token_ttl,
         refresh_token_ttl: dotenvy::var("REFRESH_TOKEN_TTL")?.parse()?,
-        access_token_key: dotenvy::var("ACCESS_TOKEN_KEY")?,
-        refresh_token_key: dotenvy::var("REFRESH_TOKEN_KEY")?,
-
-        auth_client: rpc_clients.0,
-        notes_client: rpc_clients.1,
-        tags_client: rpc_clients.2,
-        files_client: rpc_clients.3,
-        shelves_client: rpc_clients.4,
+        access_token_key: cookie_config.prefixed_key(&dotenvy::var("ACCESS_TOKEN_KEY")?, &cookie_config.path),
+        refresh_token_key: cookie_config.prefixed_key(&dotenvy::var("REFRESH_TOKEN_KEY")?, &cookie_config.refresh_path),
+        csrf_cookie_key: cookie_config.prefixed_key(&dotenvy::var("CSRF_COOKIE_KEY")?, &cookie_config.path),
+        csrf_header: dotenvy::var("CSRF_HEADER")?.parse()?,
+        cookie_config,
+        token_precedence: dotenvy::var("TOKEN_PRECEDENCE")?.parse()?,
+        auto_refresh,
+        refresh_group: Default::default(),
+        token_cache: Arc::new(TokenCache::new(
+            dotenvy::var("TOKEN_CACHE_TTL")?.parse()?,
+            access_token_ttl,
+            dotenvy::var("TOKEN_CACHE_SIZE")?.parse()?,
+        )),
+
+        fingerprint_header: dotenvy::var("FINGERPRINT_HEADER")?.parse()?,
+        fingerprint_ip_mode: dotenvy::var("FINGERPRINT_IP_MODE")?.parse()?,
+
+        auth_client: rpc_clients.auth,
+        notes_client: rpc_clients.notes,
+        tags_client: rpc_clients.tags,
+        files_client: rpc_clients.files,
+        shelves_client: rpc_clients.shelves,
+        auth_health_client: rpc_clients.auth_health,
+        data_health_client: rpc_clients.data_health,
+        readiness_timeout: dotenvy::var("READINESS_TIMEOUT")?.parse()?,
+        shutdown: Default::default(),
+        shutdown_grace_period: dotenvy::var("SHUTDOWN_GRACE_PERIOD")?.parse()?,
+        metrics_port: match dotenvy::var("METRICS_PORT") {
+            Ok(p) if !p.is_empty() => Some(p.parse()?),
+            _ => None,
+        },
     })
 }
@@ -0,0 +1,119 @@
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}};
+
+use tokio::sync::OnceCell;
+
+type RefreshResult = Result<String, tonic::Status>;
+type RefreshCell = Arc<OnceCell<RefreshResult>>;
+/// Refresh token and fingerprint, since the Auth service only accepts the refresh token along with the fingerprint it was issued for
+type RefreshKey = (String, String);
+
+/// Makes sure that concurrent requests with the same refresh token and fingerprint share a single `get_access_token` call,
+/// instead of each of them asking the Auth service for a new access token
+#[derive(Debug, Default)]
+pub struct RefreshGroup {
+    in_flight: Mutex<HashMap<RefreshKey, RefreshCell>>,
+}
+
+/// Removes the in-flight entry once the caller is done with it, even if the caller gets cancelled
+struct InFlightGuard<'a> {
+    group: &'a RefreshGroup,
+    key: RefreshKey,
+    cell: RefreshCell,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.group.in_flight.lock().unwrap();
+
+        // the first caller to finish removes the entry, so that the next refresh after this one actually calls the Auth service.
+        // a cancelled caller only removes it if nobody else is waiting for it, and the cell gets cloned only under the lock,
+        // so the map and this guard holding the only references means exactly that
+
+        let is_current = in_flight.get(&self.key).is_some_and(|c| Arc::ptr_eq(c, &self.cell));
+
+        if is_current && (self.cell.initialized() || Arc::strong_count(&self.cell) == 2) {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+impl RefreshGroup {
+    /// Runs `refresh_fn` if there is no refresh in progress for the `refresh_token` and `fingerprint`,
+    /// otherwise waits for the one in progress and returns its result
+    pub async fn refresh<F, Fut>(&self, refresh_token: &str, fingerprint: &str, refresh_fn: F) -> RefreshResult
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = RefreshResult>,
+    {
+        let key = (refresh_token.to_string(), fingerprint.to_string());
+
+        let cell = self.in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        let guard = InFlightGuard { group: self, key, cell };
+
+        guard.cell.get_or_init(refresh_fn).await.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::{AtomicU32, Ordering}, time::Duration};
+
+    use super::*;
+
+    /// Refresh that takes a moment, so that the other calls can join it, and counts how many times it has run
+    async fn slow_refresh(calls: &AtomicU32, token: &str) -> RefreshResult {
+        calls.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(token.to_string())
+    }
+
+    #[tokio::test]
+    async fn same_fingerprint_shares_call() {
+        let group = RefreshGroup::default();
+        let calls = AtomicU32::new(0);
+
+        let (a, b) = tokio::join!(
+            group.refresh("rt", "fp", || slow_refresh(&calls, "first")),
+            group.refresh("rt", "fp", || slow_refresh(&calls, "second")),
+        );
+
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert_eq!("first", a.unwrap());
+        assert_eq!("first", b.unwrap());
+        assert!(group.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn different_fingerprint_not_shared() {
+        let group = RefreshGroup::default();
+        let calls = AtomicU32::new(0);
+
+        let (a, b) = tokio::join!(
+            group.refresh("rt", "fp", || slow_refresh(&calls, "legit")),
+            group.refresh("rt", "stolen", || slow_refresh(&calls, "attacker")),
+        );
+
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+        assert_eq!("legit", a.unwrap());
+        assert_eq!("attacker", b.unwrap());
+    }
+
+    #[tokio::test]
+    async fn cancelled_refresh_removed() {
+        let group = RefreshGroup::default();
+        let calls = AtomicU32::new(0);
+
+        let cancelled = tokio::time::timeout(Duration::from_millis(10), group.refresh("rt", "fp", || slow_refresh(&calls, "cancelled"))).await;
+        assert!(cancelled.is_err());
+        assert!(group.in_flight.lock().unwrap().is_empty());
+
+        let result = group.refresh("rt", "fp", || slow_refresh(&calls, "retried")).await;
+        assert_eq!("retried", result.unwrap());
+    }
+}
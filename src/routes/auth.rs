@@ -1,13 +1,15 @@
-use crate::{error::ResError, proto::auth::{GetAtRequest, LoginRequest, LogoutRequest, RegisterRequest}, types::{call_grpc_service, new_cookie_ok_res, ExRes400, ExRes401, ExRes415, ExRes422, ExRes5XX, Json}};
-use crate::types::{CookieResult, AppState, CreateAndAddCookie};
+use crate::{error::ResError, proto::auth::{GetAtRequest, LoginRequest, LoginResponse, LogoutRequest, RegisterRequest}, types::{call_grpc_service, get_token, new_cookie_ok_res, new_ok_res, ExRes400, ExRes401, ExRes415, ExRes422, ExRes5XX, Json}};
+use crate::types::{CookieResult, AppState, CreateAndAddCookie, ServerResult};
+use crate::{csrf::{get_or_new_csrf_token, new_csrf_token}, fingerprint::Fingerprint};
 
-use axum::{extract::State, routing::{get, post}, Router};
+use axum::{extract::State, http::{HeaderMap, StatusCode}, routing::{get, post}, Router};
 use axum_extra::extract::cookie::CookieJar;
 use utoipa::OpenApi;
 
 pub fn get_router(state: &AppState) -> Router {
     Router::new()
         .route("/login", post(login_post))
+        .route("/login/token", post(login_token_post))
         .route("/register", post(register_post))
         .route("/access", get(access_get))
         .route("/logout", get(logout_get))
@@ -16,18 +18,18 @@ pub fn get_router(state: &AppState) -> Router {
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(login_post, register_post, access_get, logout_get),
-    components(schemas(LoginRequest, RegisterRequest)),
+    paths(login_post, login_token_post, register_post, access_get, logout_get),
+    components(schemas(LoginRequest, LoginResponse, RegisterRequest)),
 )]
 pub struct Api;
 
 /// Log in
 ///
-/// Log in as an existing user using credentials
+/// Log in as an existing user using credentials. The client should also send its fingerprint in the header that is specified by `FINGERPRINT_HEADER` in the service's .env, and then send the same fingerprint on each `/access` call
 #[utoipa::path(
     post, path = "login",
     responses(
-        (status = 200, description = "Success", headers(("set-cookie", description = "Two cookies that include new access and refresh tokens"))),
+        (status = 200, description = "Success", headers(("set-cookie", description = "Three cookies that include new access and refresh tokens, and the csrf token"))),
         ExRes400, ExRes415, ExRes422, ExRes5XX,
     ),
     security(()),
@@ -36,13 +38,11 @@ pub struct Api;
 async fn login_post(
     jar: CookieJar,
     State(mut state): State<AppState>,
+    Fingerprint(fingerprint): Fingerprint,
     Json(mut body): Json<LoginRequest>,
 ) -> CookieResult {
 
-    // in the future, the frontend will generate a fingerprint and pass it in the headers
-
-    let fingerprint = "asdfasdf";
-    body.fingerprint = fingerprint.into();
+    body.fingerprint = fingerprint;
 
     // calling the grpc auth api
 
@@ -50,24 +50,59 @@ async fn login_post(
         body,
         |req| state.auth_client.login(req),
         &state.auth_token,
+        "auth.login",
     ).await?;
 
     // sending the cookies
 
+    let csrf_token = new_csrf_token();
+    let cookies = &state.cookie_config;
+
     new_cookie_ok_res(
         jar
-            .add_new_cookie(state.refresh_token_key, res_body.refresh_token, state.refresh_token_ttl)
-            .add_new_cookie(state.access_token_key, res_body.access_token, state.access_token_ttl)
+            .add_new_cookie(state.refresh_token_key, res_body.refresh_token, state.refresh_token_ttl, &cookies.refresh_path, cookies)
+            .add_new_cookie(state.access_token_key, res_body.access_token, state.access_token_ttl, &cookies.path, cookies)
+            .add_new_csrf_cookie(state.csrf_cookie_key, csrf_token, state.refresh_token_ttl, cookies)
     )
 }
 
+/// Log in without cookies
+///
+/// Same as `/login`, but the tokens get returned in the response body instead of cookies. Meant for non-browser clients, which should then send the access token in the `Authorization: Bearer` header
+#[utoipa::path(
+    post, path = "login/token",
+    responses(
+        (status = 200, description = "Success", body = LoginResponse),
+        ExRes400, ExRes415, ExRes422, ExRes5XX,
+    ),
+    security(()),
+)]
+#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+async fn login_token_post(
+    State(mut state): State<AppState>,
+    Fingerprint(fingerprint): Fingerprint,
+    Json(mut body): Json<LoginRequest>,
+) -> ServerResult<LoginResponse> {
+
+    body.fingerprint = fingerprint;
+
+    let res_body = call_grpc_service(
+        body,
+        |req| state.auth_client.login(req),
+        &state.auth_token,
+        "auth.login",
+    ).await?;
+
+    new_ok_res(StatusCode::OK, res_body)
+}
+
 /// Register
 ///
-/// Register as a new user using new credentials
+/// Register as a new user using new credentials. Fingerprint rules are the same as in `/login`
 #[utoipa::path(
     post, path = "register",
     responses(
-        (status = 200, description = "Success", headers(("set-cookie", description = "Two cookies that include new access and refresh tokens"))),
+        (status = 200, description = "Success", headers(("set-cookie", description = "Three cookies that include new access and refresh tokens, and the csrf token"))),
         ExRes400, ExRes415, ExRes422, ExRes5XX,
     ),
     security(()),
@@ -76,32 +111,37 @@ async fn login_post(
 async fn register_post(
     jar: CookieJar,
     State(mut state): State<AppState>,
+    Fingerprint(fingerprint): Fingerprint,
     Json(mut body): Json<RegisterRequest>,
 ) -> CookieResult {
 
-    let fingerprint = "asdfasdf";
-    body.fingerprint = fingerprint.into();
+    body.fingerprint = fingerprint;
 
     let res_body = call_grpc_service(
         body,
         |req| state.auth_client.register(req),
         &state.auth_token,
+        "auth.register",
     ).await?;
 
+    let csrf_token = new_csrf_token();
+    let cookies = &state.cookie_config;
+
     new_cookie_ok_res(
         jar
-            .add_new_cookie(state.refresh_token_key, res_body.refresh_token, state.refresh_token_ttl)
-            .add_new_cookie(state.access_token_key, res_body.access_token, state.access_token_ttl)
+            .add_new_cookie(state.refresh_token_key, res_body.refresh_token, state.refresh_token_ttl, &cookies.refresh_path, cookies)
+            .add_new_cookie(state.access_token_key, res_body.access_token, state.access_token_ttl, &cookies.path, cookies)
+            .add_new_csrf_cookie(state.csrf_cookie_key, csrf_token, state.refresh_token_ttl, cookies)
     )
 }
 
 /// Get a new access token
 ///
-/// Once an existing access token expires, this route should be called to get a new one. If both access and refresh tokens expire, then the user will have to log in manually
+/// Once an existing access token expires, this route should be called to get a new one. If both access and refresh tokens expire, or if the client's fingerprint doesn't match the one it logged in with, then the user will have to log in manually
 #[utoipa::path(
     get, path = "access",
     responses(
-        (status = 200, description = "Success", headers(("set-cookie", description = "Cookie that includes the new access token"))),
+        (status = 200, description = "Success", headers(("set-cookie", description = "Two cookies that include the new access token and the csrf token"))),
         ExRes401, ExRes5XX,
     ),
     security(("refresh_token" = [])),
@@ -109,57 +149,87 @@ async fn register_post(
 #[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
 async fn access_get(
     jar: CookieJar,
-    State(mut state): State<AppState>,
+    State(state): State<AppState>,
+    Fingerprint(fingerprint): Fingerprint,
 ) -> CookieResult {
 
-    let fingerprint = "asdfasdf";
-
     let token = jar.get(&state.refresh_token_key)
         .ok_or(ResError::Unauthorized("Could not get a refresh token".into()))?
         .value();
 
-    let res_body = call_grpc_service(
-        GetAtRequest { refresh_token: token.into(), fingerprint: fingerprint.into() },
-        |req| state.auth_client.get_access_token(req),
-        &state.auth_token,
-    ).await?;
+    let access_token = get_new_access_token(&state, token, fingerprint).await?;
+
+    let csrf_token = get_or_new_csrf_token(&jar, &state);
+    let cookies = &state.cookie_config;
 
     new_cookie_ok_res(
         jar
-            .add_new_cookie(state.access_token_key, res_body.access_token, state.access_token_ttl)
+            .add_new_cookie(state.access_token_key, access_token, state.access_token_ttl, &cookies.path, cookies)
+            .add_new_csrf_cookie(state.csrf_cookie_key, csrf_token, state.refresh_token_ttl, cookies)
     )
 }
 
+/// Gets a new access token from the Auth service. Concurrent calls with the same refresh token and fingerprint share a single Auth service call
+pub async fn get_new_access_token(state: &AppState, refresh_token: &str, fingerprint: String) -> Result<String, ResError> {
+    let mut auth_client = state.auth_client.clone();
+    let auth_token = state.auth_token.clone();
+    let req_body = GetAtRequest { refresh_token: refresh_token.into(), fingerprint: fingerprint.clone() };
+
+    // the Auth service rejects the refresh token if the fingerprint differs from the one used on login,
+    // which from the client's perspective means that it has to log in again. that's also why only the calls
+    // with the same fingerprint get shared, so that a stolen refresh token can't ride along with the legit refresh
+
+    state.refresh_group.refresh(refresh_token, &fingerprint, || async move {
+        call_grpc_service(
+            req_body,
+            |req| auth_client.get_access_token(req),
+            &auth_token,
+            "auth.get_access_token",
+        ).await.map(|res_body| res_body.access_token)
+    }).await.map_err(|e| match e.code() {
+        tonic::Code::PermissionDenied => ResError::Unauthorized(e.to_string()),
+        _ => e.into(),
+    })
+}
+
 /// Log out
 #[utoipa::path(
     get, path = "logout",
     responses(
-        (status = 200, description = "Success", headers(("set-cookie", description = "Two cookies that erase access and refresh tokens"))),
+        (status = 200, description = "Success", headers(("set-cookie", description = "Three cookies that erase access, refresh and csrf tokens"))),
         ExRes401, ExRes5XX,
     ),
-    security(("access_token" = [])),
+    security(("access_token" = []), ("bearer_token" = [])),
 )]
 #[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
 async fn logout_get(
     jar: CookieJar,
+    headers: HeaderMap,
     State(mut state): State<AppState>,
+    Fingerprint(fingerprint): Fingerprint,
 ) -> CookieResult {
 
-    let fingerprint = "asdfasdf";
-
-    let token = jar.get(&state.access_token_key)
-        .ok_or(ResError::Unauthorized("Could not get an access token".into()))?
-        .value();
+    let (token, _) = get_token(&jar, &headers, &state.access_token_key, state.token_precedence)
+        .ok_or(ResError::Unauthorized("Could not get an access token".into()))?;
 
     call_grpc_service(
-        LogoutRequest { access_token: token.into(), fingerprint: fingerprint.into() },
+        LogoutRequest { access_token: token.into(), fingerprint },
         |req| state.auth_client.logout(req),
         &state.auth_token,
+        "auth.logout",
     ).await?;
 
+    state.token_cache.invalidate(token);
+    state.events.logout(token);
+
+    // the refresh cookie doesn't get sent here if its path is scoped, but it can still be erased by setting it on the same path
+
+    let cookies = &state.cookie_config;
+
     new_cookie_ok_res(
         jar
-            .add_new_cookie(state.refresh_token_key, "".into(), 0)
-            .add_new_cookie(state.access_token_key, "".into(), 0)
+            .add_new_cookie(state.refresh_token_key, "".into(), 0, &cookies.refresh_path, cookies)
+            .add_new_cookie(state.access_token_key, "".into(), 0, &cookies.path, cookies)
+            .add_new_csrf_cookie(state.csrf_cookie_key, "".into(), 0, cookies)
     )
 }
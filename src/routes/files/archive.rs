@@ -0,0 +1,237 @@
+use std::{collections::HashSet, pin::pin};
+
+use async_stream::stream;
+use async_zip::{base::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use axum::body::Body;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use futures_util::{AsyncWriteExt, StreamExt};
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error};
+
+use super::disposition::content_disposition;
+use crate::proto::files::{DownloadFileReq, File};
+use crate::types::{call_grpc_service, AppState};
+use crate::telemetry;
+
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Size of the buffer between the zip writer and the response body
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Describes an archived file. Gets written into the manifest at the end of the archive
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    /// Name of the file inside of the archive, which differs from the original name in case of collisions
+    path: String,
+    name: String,
+    id: i32,
+    hash: String,
+    size: i64,
+    created: i64,
+    /// Why the file is missing or incomplete
+    error: Option<String>,
+}
+
+/// Streams the files into a zip archive. Files get downloaded one by one and are never fully loaded into memory.
+/// The files are stored without compression, since most attachments are already compressed anyway.
+/// The archive gets written by the response body itself, so it's tracked as in flight during shutdown, and it stops once the client goes away
+pub fn zip_response(state: &AppState, user_id: i32, archive_name: &str, files: Vec<File>) -> Response {
+    let (reader, writer) = tokio::io::duplex(PIPE_SIZE);
+    let writing = write_archive(state.clone(), user_id, files, writer);
+
+    let body = stream! {
+        let mut writing = pin!(writing);
+        let mut written = false;
+        let mut reader = ReaderStream::new(reader);
+
+        // once the writer is done, it closes its end of the pipe, and the reader ends after the rest of the data
+
+        loop {
+            let chunk = tokio::select! {
+                _ = &mut writing, if !written => {
+                    written = true;
+                    continue;
+                },
+                chunk = reader.next() => chunk,
+            };
+
+            match chunk {
+                Some(chunk) => yield chunk,
+                None => break,
+            }
+        }
+    };
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, content_disposition(archive_name, "application/zip", false)),
+        ],
+        Body::from_stream(body),
+    ).into_response()
+}
+
+async fn write_archive(mut state: AppState, user_id: i32, files: Vec<File>, writer: impl AsyncWrite + Unpin) {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    let mut used_paths = HashSet::from([MANIFEST_NAME.to_string()]);
+    let mut manifest = Vec::with_capacity(files.len());
+
+    for file in files {
+        let path = unique_path(&mut used_paths, &file.name);
+
+        // if the client goes away, the pipe breaks and there's no point in continuing
+
+        let error = match write_file(&mut state, user_id, &mut zip, &path, &file.hash).await {
+            Ok(()) => None,
+            Err(FileError::Pipe(e)) => return debug!("Stopped writing an archive: {e}"),
+            Err(FileError::Download(e)) => Some(e),
+        };
+
+        manifest.push(ManifestEntry {
+            path, name: file.name, id: file.id, hash: file.hash, size: file.size, created: file.created, error,
+        });
+    }
+
+    let manifest = match serde_json::to_vec_pretty(&manifest) {
+        Ok(m) => m,
+        Err(e) => return error!("Could not serialize an archive manifest: {e}"),
+    };
+
+    let entry = ZipEntryBuilder::new(MANIFEST_NAME.to_string().into(), Compression::Stored);
+
+    if let Err(e) = zip.write_entry_whole(entry, &manifest).await {
+        return debug!("Stopped writing an archive: {e}");
+    }
+
+    if let Err(e) = zip.close().await {
+        debug!("Could not finish writing an archive: {e}");
+    }
+}
+
+enum FileError {
+    /// The file couldn't be downloaded, which gets noted in the manifest
+    Download(String),
+    /// The archive itself couldn't be written
+    Pipe(String),
+}
+
+async fn write_file<W>(
+    state: &mut AppState,
+    user_id: i32,
+    zip: &mut ZipFileWriter<W>,
+    path: &str,
+    file_hash: &str,
+) -> Result<(), FileError>
+where
+    W: futures_util::AsyncWrite + Unpin,
+{
+    let stream = call_grpc_service(
+        DownloadFileReq { user_id, file_hash: file_hash.to_string() },
+        |req| state.files_client.download_file(req),
+        &state.data_token,
+        "files.download_file",
+    ).await.map_err(|e| FileError::Download(e.message().to_string()))?;
+
+    let mut stream = pin!(stream.take_until(state.shutdown.stream_token().cancelled_owned()));
+
+    let entry = ZipEntryBuilder::new(path.to_string().into(), Compression::Stored);
+    let mut entry_writer = zip.write_entry_stream(entry).await.map_err(|e| FileError::Pipe(e.to_string()))?;
+
+    // the entry still has to be closed if the download fails midway, so that the rest of the archive stays valid
+
+    let mut result = Ok(());
+
+    while let Some(part) = stream.next().await {
+        let part = match part {
+            Ok(p) => p,
+            Err(e) => {
+                result = Err(FileError::Download(format!("The download failed midway: {}", e.message())));
+                break;
+            },
+        };
+
+        telemetry::record_downloaded_bytes(part.data.len());
+        entry_writer.write_all(&part.data).await.map_err(|e| FileError::Pipe(e.to_string()))?;
+    }
+
+    if state.shutdown.stream_token().is_cancelled() {
+        result = Err(FileError::Download("The download was cancelled because the service is shutting down".into()));
+    }
+
+    entry_writer.close().await.map_err(|e| FileError::Pipe(e.to_string()))?;
+
+    result
+}
+
+/// Makes the file name safe to use as a path inside of the archive, and makes sure that it's unique
+/// by adding a number to it, the same way that browsers do with downloads: `name.txt`, `name (1).txt`, `name (2).txt`
+fn unique_path(used_paths: &mut HashSet<String>, name: &str) -> String {
+    let name = name
+        .replace(['/', '\\'], "_")
+        .trim_start_matches('.')
+        .to_string();
+
+    let name = match name.is_empty() {
+        true => "file".to_string(),
+        false => name,
+    };
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem.to_string(), format!(".{extension}")),
+        _ => (name.clone(), String::new()),
+    };
+
+    let mut path = name;
+    let mut i = 1;
+
+    while !used_paths.insert(path.to_lowercase()) {
+        path = format!("{stem} ({i}){extension}");
+        i += 1;
+    }
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<String> {
+        let mut used_paths = HashSet::from([MANIFEST_NAME.to_string()]);
+        names.iter().map(|name| unique_path(&mut used_paths, name)).collect()
+    }
+
+    #[test]
+    fn duplicates_numbered() {
+        assert_eq!(
+            vec!["a.txt", "a (1).txt", "a (2).txt", "b", "b (1)"],
+            paths(&["a.txt", "a.txt", "a.txt", "b", "b"]),
+        );
+    }
+
+    #[test]
+    fn case_collisions_numbered() {
+        assert_eq!(vec!["Photo.JPG", "photo (1).jpg"], paths(&["Photo.JPG", "photo.jpg"]));
+    }
+
+    #[test]
+    fn manifest_name_reserved() {
+        assert_eq!(vec!["manifest (1).json"], paths(&["manifest.json"]));
+    }
+
+    #[test]
+    fn traversal_flattened() {
+        assert_eq!(
+            vec!["_etc_passwd", "_.._secret.txt", "a_b_c.txt"],
+            paths(&["../etc/passwd", "..\\../secret.txt", "a/b\\c.txt"]),
+        );
+    }
+
+    #[test]
+    fn dotfiles_renamed() {
+        assert_eq!(vec!["env", "file", "file (1)", "hidden.txt"], paths(&[".env", "..", "", "..hidden.txt"]));
+    }
+}
@@ -0,0 +1,103 @@
+use serde::Deserialize;
+
+use crate::error::ResError;
+
+/// Types that browsers can display on their own without running any scripts from the file.
+/// Everything else, including HTML and SVG, always gets downloaded
+const INLINE_TYPES: &[&str] = &[
+    "application/pdf",
+    "text/plain",
+    "image/png", "image/jpeg", "image/gif", "image/webp", "image/avif", "image/bmp",
+    "audio/mpeg", "audio/ogg", "audio/wav", "audio/webm", "audio/flac", "audio/aac",
+    "video/mp4", "video/webm", "video/ogg",
+];
+
+/// helper struct that the download url query deserializes into
+#[derive(Debug, Default, Deserialize)]
+pub struct DownloadQuery {
+    disposition: Option<String>,
+}
+
+impl DownloadQuery {
+    /// Whether the client asked to display the file in the browser instead of downloading it
+    pub fn inline(&self) -> Result<bool, ResError> {
+        match self.disposition.as_deref() {
+            None | Some("attachment") => Ok(false),
+            Some("inline") => Ok(true),
+            Some(d) => Err(ResError::InvalidFields(format!("Received invalid query field: {d}"))),
+        }
+    }
+}
+
+pub fn content_type(file_name: &str) -> String {
+    mime_guess::from_path(file_name)
+        .first()
+        .map(|m| m.essence_str().to_string())
+        .unwrap_or("application/octet-stream".into())
+}
+
+/// Builds the `Content-Disposition` header value. The file is only shown inline if it was asked for and its type is safe to show,
+/// since a file that the browser renders as a page would otherwise run on this service's origin
+pub fn content_disposition(file_name: &str, content_type: &str, inline: bool) -> String {
+    let disposition = match inline && INLINE_TYPES.contains(&content_type) {
+        true => "inline",
+        false => "attachment",
+    };
+
+    // old clients only understand the plain `filename`, so it gets an ascii fallback,
+    // while the actual name goes into `filename*` as described in RFC 6266 and RFC 5987
+
+    let fallback: String = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+
+    match fallback == file_name {
+        true => format!("{disposition}; filename=\"{file_name}\""),
+        false => format!("{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{}", percent_encode(file_name)),
+    }
+}
+
+/// Encodes everything except the `attr-char` characters from RFC 5987
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9'
+            | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scriptable_types_never_inline() {
+        assert_eq!("attachment; filename=\"page.html\"", content_disposition("page.html", "text/html", true));
+        assert_eq!("attachment; filename=\"icon.svg\"", content_disposition("icon.svg", "image/svg+xml", true));
+        assert_eq!("inline; filename=\"photo.png\"", content_disposition("photo.png", "image/png", true));
+        assert_eq!("attachment; filename=\"photo.png\"", content_disposition("photo.png", "image/png", false));
+    }
+
+    #[test]
+    fn non_ascii_name_encoded() {
+        assert_eq!(
+            "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf",
+            content_disposition("résumé.pdf", "application/pdf", false),
+        );
+    }
+
+    #[test]
+    fn special_characters_replaced_in_fallback() {
+        assert_eq!(
+            "attachment; filename=\"a_b_c__d.txt\"; filename*=UTF-8''a%22b%5Cc%0D%0Ad.txt",
+            content_disposition("a\"b\\c\r\nd.txt", "text/plain", false),
+        );
+    }
+}
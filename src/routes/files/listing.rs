@@ -0,0 +1,122 @@
+use crate::error::ResError;
+use crate::proto::files::File;
+use crate::proto::notes::{filters, sort, Filters, Note, NoteList, Pagination, ReadNotesReq, Sort};
+use crate::proto::shelves::{ReadShelfReq, Shelf};
+use crate::types::{call_grpc_service, AppState};
+
+/// How many notes get read per request when going through all of them
+const NOTES_PER_PAGE: i32 = 100;
+
+pub async fn read_shelf(state: &AppState, user_id: i32) -> Result<Shelf, ResError> {
+    let mut state = state.clone();
+
+    Ok(call_grpc_service(
+        ReadShelfReq { user_id },
+        |req| state.shelves_client.read_shelf(req),
+        &state.data_token,
+        "shelves.read_shelf",
+    ).await?)
+}
+
+async fn read_notes_page(state: &AppState, user_id: i32, page: i32, filters: Filters) -> Result<NoteList, ResError> {
+    let mut state = state.clone();
+
+    Ok(call_grpc_service(
+        ReadNotesReq {
+            user_id,
+            pagination: Some(Pagination { page, per_page: NOTES_PER_PAGE }),
+            sort: Some(Sort { sort_type: sort::Type::Asc.into(), sort_field: sort::Field::Date.into() }),
+            filters: Some(filters),
+        },
+        |req| state.notes_client.read_notes(req),
+        &state.data_token,
+        "notes.read_notes",
+    ).await?)
+}
+
+/// Goes through the pages of the user's notes until `found` returns something
+async fn find_in_notes<T>(
+    state: &AppState,
+    user_id: i32,
+    filters: Filters,
+    mut found: impl FnMut(Vec<Note>) -> Option<T>,
+) -> Result<Option<T>, ResError> {
+    let mut read = 0;
+    let mut page = 1;
+
+    loop {
+        let note_list = read_notes_page(state, user_id, page, filters.clone()).await?;
+
+        if note_list.notes.is_empty() {
+            return Ok(None);
+        }
+
+        read += note_list.notes.len() as i64;
+
+        if let Some(v) = found(note_list.notes) {
+            return Ok(Some(v));
+        }
+
+        if read >= note_list.total_count as i64 {
+            return Ok(None);
+        }
+
+        page += 1;
+    }
+}
+
+/// Reads every page of the user's notes
+pub async fn read_all_notes(state: &AppState, user_id: i32) -> Result<Vec<Note>, ResError> {
+    let mut notes = Vec::new();
+
+    find_in_notes(state, user_id, Filters::default(), |page| {
+        notes.extend(page);
+        None::<()>
+    }).await?;
+
+    Ok(notes)
+}
+
+/// Finds a single note. The Data service can't read a single note, so this goes through the pages of notes until it's found
+pub async fn find_note(state: &AppState, user_id: i32, note_id: i32) -> Result<Option<Note>, ResError> {
+    find_in_notes(state, user_id, Filters::default(), |page| {
+        page.into_iter().find(|n| n.id == note_id)
+    }).await
+}
+
+/// Finds a single note among the notes that were last edited at `last_edited`, which usually takes a single request.
+/// Returns `None` if the note has been edited at any other time
+pub async fn find_note_edited_at(state: &AppState, user_id: i32, note_id: i32, last_edited: i64) -> Result<Option<Note>, ResError> {
+    let filters = Filters {
+        filter_date_modif: Some(filters::DateModif { start: last_edited, end: last_edited }),
+        ..Default::default()
+    };
+
+    find_in_notes(state, user_id, filters, |page| {
+        page.into_iter().find(|n| n.id == note_id)
+    }).await
+}
+
+/// Finds a single file. The shelf gets checked first, since it only takes a single request
+pub async fn find_file(state: &AppState, user_id: i32, file_id: i32) -> Result<Option<File>, ResError> {
+    let shelf = read_shelf(state, user_id).await?;
+
+    if let Some(file) = shelf.files.into_iter().find(|f| f.id == file_id) {
+        return Ok(Some(file));
+    }
+
+    find_in_notes(state, user_id, Filters::default(), |page| {
+        page.into_iter().flat_map(|n| n.files).find(|f| f.id == file_id)
+    }).await
+}
+
+/// Gets all of the files in the user's shelf and in all of the user's notes.
+/// The Data service has no way to list the files directly, so this has to go through every page of notes
+pub async fn list_files(state: &AppState, user_id: i32) -> Result<Vec<File>, ResError> {
+    let shelf = read_shelf(state, user_id).await?;
+    let notes = read_all_notes(state, user_id).await?;
+
+    Ok(shelf.files.into_iter()
+        .chain(notes.into_iter().flat_map(|n| n.files))
+        .collect())
+}
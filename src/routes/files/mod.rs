@@ -0,0 +1,648 @@
+use crate::proto::files::create_file_metadata::AttachId;
+use crate::proto::files::{CreateFileMetadata, DeleteFileReq, DownloadFileMetadata, DownloadFileReq, Empty, File, FileData};
+use crate::types::{call_grpc_service, new_ok_res, unix_now, Json, ResultBody, ExRes400, ExRes401, ExRes404, ExRes415, ExRes422, ExRes5XX};
+use crate::events::ChangeEvent;
+use crate::idempotency::idempotency_middleware;
+use crate::links::{LinkClaims, LinkSigner};
+use crate::thumbnail_cache::ThumbnailCache;
+use crate::upload_progress::{ProgressReporter, UploadProgress};
+use crate::{types::{AppState, ServerResult}, error::ResError, telemetry};
+
+use axum::body::{Body, Bytes};
+use axum::extract::{multipart, Multipart, Query};
+use axum::handler::Handler;
+use axum::http::{header, HeaderMap};
+use axum::middleware;
+use axum::response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response};
+use axum::routing::delete;
+use axum::{Router, routing::{post, get}, extract::{DefaultBodyLimit, State, Path}, Extension, http::StatusCode};
+use futures_util::{Stream, StreamExt};
+use tokio_util::io::ReaderStream;
+use tower_http::limit::RequestBodyLimitLayer;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use utoipa::{OpenApi, ToSchema};
+
+use disposition::{content_disposition, content_type, DownloadQuery};
+use thumbnail::{check_source, generate, read_source, ThumbnailQuery};
+use range::{etag_matches, parse_range, slice_stream, RangeResult};
+use listing::find_file;
+use quota::{get_usage, QuotaSession, Usage};
+use upload::{create_file, spool_field, UploadedFile};
+pub mod archive;
+pub mod disposition;
+pub mod listing;
+pub mod quota;
+mod range;
+pub mod tus;
+mod thumbnail;
+mod upload;
+
+pub fn get_router(state: &AppState) -> Router {
+    Router::new()
+        .route("/", post(files_post.layer(middleware::from_fn_with_state(state.clone(), idempotency_middleware))))
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(
+            1024 * 1024 * state.req_body_limit,
+        ))
+        .route("/:id", delete(files_delete))
+        .route("/:id/link", post(files_link_post))
+        .route("/dl/:hash", get(files_dl_get))
+        .route("/dl/:hash/thumb", get(files_thumb_get))
+        .route("/usage", get(files_usage_get))
+        .route("/progress/:upload_id", get(files_progress_get))
+        .nest("/tus", tus::get_router(state))
+        .with_state(state.clone())
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        files_post, files_dl_get, files_thumb_get, files_delete, files_usage_get, files_link_post, files_progress_get,
+        tus::tus_options, tus::tus_post, tus::tus_head, tus::tus_patch, tus::tus_delete,
+    ),
+    components(schemas(ExampleMultipartBody, UploadedFile, UploadProgress, Usage, CreateLinkReq, PublicLink, File, Empty)),
+    security(("access_token" = []), ("bearer_token" = [])),
+)]
+pub struct Api;
+
+#[derive(ToSchema)]
+#[schema(title = "MultipartBody")]
+#[allow(dead_code)]
+struct ExampleMultipartBody {
+    note_id: Option<i32>,
+    shelf_id: Option<i32>,
+    file_size: Option<u64>,
+    checksum: Option<String>,
+    file: Vec<u8>,
+}
+
+// impl to convert tonic stream directly into axum body (for files_dl_get)
+impl From<FileData> for axum::body::Bytes {
+    fn from(value: FileData) -> Self {
+        value.data.into()
+    }
+}
+
+/// Where the files from a multipart body get attached. Can come in any order relative to the files
+fn set_attach_id(attach_id: &mut Option<AttachId>, new_id: AttachId) -> Result<(), ResError> {
+    match attach_id.replace(new_id) {
+        Some(_) => Err(ResError::InvalidFields("The multipart body must contain exactly one of note_id and shelf_id".into())),
+        None => Ok(()),
+    }
+}
+
+/// Gets the file size from the part's `Content-Length` header
+fn part_content_length(field: &multipart::Field<'_>) -> Option<u64> {
+    field.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// helper struct that the `files_post` url query deserializes into
+#[derive(Debug, Default, Deserialize)]
+struct UploadQuery {
+    upload_id: Option<String>,
+}
+
+/// Uploads a file, if it fits into the user's quota
+async fn upload_file<S, E>(
+    state: &AppState,
+    quota: Option<&QuotaSession>,
+    metadata: CreateFileMetadata,
+    checksum: Option<String>,
+    data: S,
+    progress: (&ProgressReporter, usize),
+) -> Result<(File, String), ResError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<ResError>,
+{
+    let file_size = metadata.file_size;
+    let (user_id, attach_id) = (metadata.user_id, metadata.attach_id);
+
+    let reservation = match quota {
+        Some(quota) => Some(quota.reserve(state, file_size, None).await?),
+        None => None,
+    };
+
+    let (progress, index) = progress;
+    progress.start_file(index, &metadata.name, file_size);
+
+    let result = create_file(state, metadata, checksum, data, |len| progress.chunk_sent(index, len)).await;
+
+    if let (Ok(_), Some(reservation)) = (&result, reservation) {
+        reservation.file_created().await;
+    }
+
+    if let (Ok((file, _)), Some(attach_id)) = (&result, attach_id) {
+        state.events.publish(user_id, ChangeEvent::file_created(file.clone(), attach_id));
+    }
+
+    result
+}
+
+/// Create new files
+///
+/// Post (upload) one or more files and immediately attach them to either a note or a shelf.
+/// Each file gets uploaded separately, so the response contains a result for each of them.
+/// The upload's progress can be followed at `/files/progress/{upload_id}` by passing an `upload_id`
+#[utoipa::path(
+    post, path = "",
+    params(
+        ("upload_id" = Option<String>, Query, description = "Id that the client chooses for following the upload's progress. v must follow the `[A-Za-z0-9_-]{1,64}` regex."),
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes the request safe to retry. Repeats with the same key get the first response back"),
+    ),
+    request_body(content = ExampleMultipartBody, content_type = "multipart/form-data", description = "Note that despite `note_id` and `shelf_id` are showing as optional, you must always specify exactly one of them.<br>The body can contain multiple `file` parts, and the fields can come in any order. However, files that come before `note_id`/`shelf_id` or without a known size have to be temporarily saved on the gateway's side first, so it's faster to send the fields first.<br>The size of a file is taken either from the `file_size` field right before it, or from the part's `Content-Length` header. The received data must match the size, and the hex encoded SHA-256 from an optional `checksum` field right before the file"),
+    responses(
+        (status = 201, description = "All files have been successfully uploaded", body = Vec<UploadedFile>),
+        (status = 200, description = "Some of the files could not be uploaded. Their `error` field describes why", body = Vec<UploadedFile>),
+        (status = 422, description = "None of the files could be uploaded, and the first one failed because its data did not match its `file_size` or `checksum`, or because the virus scanner has found something in it", body = Vec<UploadedFile>),
+        (status = 507, description = "None of the files could be uploaded, and the first one failed because it did not fit into the user's quota", body = Vec<UploadedFile>),
+        (status = 409, description = "Another upload with the same `upload_id` is still in progress, or the idempotency key has been used for a different request, or the first request with it is still in progress"),
+        ExRes400, ExRes401, ExRes404, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(fields(attach_id), skip(state, multipart), err(level = tracing::Level::DEBUG))]
+async fn files_post(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<i32>,
+    Query(query): Query<UploadQuery>,
+    mut multipart: Multipart,
+) -> ServerResult<Vec<UploadedFile>> {
+
+    // if the request fails as a whole, the reporter gets dropped, which reports the failure
+
+    let progress = match query.upload_id {
+        Some(upload_id) => state.upload_progress.start(user_id, upload_id)?,
+        None => ProgressReporter::none(),
+    };
+
+    let mut uploaded = Vec::new();
+    let mut spooled = Vec::new();
+    let mut attach_id = None;
+    let mut file_size = None;
+    let mut checksum = None;
+
+    // the usage only gets fetched once the first file is about to be uploaded, and then each file gets counted in as it goes
+
+    let quota = state.quota_tracker.session(&state, user_id);
+
+    // files get streamed straight to the Data service if everything about them is already known,
+    // and the rest get saved into temporary files until the whole body has been read
+
+    while let Some(mut field) = multipart.next_field().await? {
+        match field.name() {
+            Some("note_id") => set_attach_id(&mut attach_id, AttachId::NoteId(field.text().await?.parse()?))?,
+            Some("shelf_id") => set_attach_id(&mut attach_id, AttachId::ShelfId(field.text().await?.parse()?))?,
+            Some("file_size") => file_size = Some(field.text().await?.parse()?),
+            Some("checksum") => checksum = Some(field.text().await?),
+            Some("file") => {
+                let name = field.file_name().map(String::from).unwrap_or_default();
+                let file_size = file_size.take().or_else(|| part_content_length(&field));
+                let checksum = checksum.take();
+
+                match (attach_id, file_size) {
+                    (Some(attach_id), Some(file_size)) => {
+                        let i = uploaded.len();
+                        let metadata = CreateFileMetadata { user_id, name: name.clone(), attach_id: Some(attach_id), file_size };
+                        let result = upload_file(&state, quota.as_ref(), metadata, checksum, field, (&progress, i)).await;
+
+                        let uploaded_file = UploadedFile::new(name, result);
+                        progress.finish_file(i, uploaded_file.file.as_ref(), uploaded_file.error.as_deref());
+                        uploaded.push(uploaded_file);
+                    },
+                    _ => {
+                        // the declared size still has to match, if there is one
+                        let (temp_file, spooled_size) = spool_field(&state, &mut field).await?;
+                        let file_size = file_size.unwrap_or(spooled_size);
+                        progress.start_file(uploaded.len(), &name, file_size);
+
+                        spooled.push((uploaded.len(), temp_file, file_size, checksum));
+                        uploaded.push(UploadedFile { name, file: None, sha256: None, error: None, error_status: None });
+                    },
+                }
+            },
+            name => debug!("Ignoring an unknown multipart field: {name:?}"),
+        }
+    }
+
+    tracing::Span::current().record("attach_id", format!("{attach_id:?}"));
+
+    if uploaded.is_empty() {
+        return Err(ResError::InvalidFields("Could not get any files from the multipart body".into()));
+    }
+
+    for (i, temp_file, file_size, checksum) in spooled {
+        let name = std::mem::take(&mut uploaded[i].name);
+
+        let result = match attach_id {
+            Some(attach_id) => {
+                let metadata = CreateFileMetadata { user_id, name: name.clone(), attach_id: Some(attach_id), file_size };
+                let file = tokio::fs::File::open(&temp_file.path).await?;
+                upload_file(&state, quota.as_ref(), metadata, checksum, ReaderStream::new(file), (&progress, i)).await
+            },
+            None => Err(ResError::InvalidFields("Could not get either note_id nor shelf_id from the multipart body".into())),
+        };
+
+        uploaded[i] = UploadedFile::new(name, result);
+        progress.finish_file(i, uploaded[i].file.as_ref(), uploaded[i].error.as_deref());
+    }
+
+    progress.finish(None);
+
+    // the response only fails as a whole if none of the files got uploaded
+
+    match uploaded.iter().find_map(|f| f.error_status) {
+        None => new_ok_res(StatusCode::CREATED, uploaded),
+        Some(_) if uploaded.iter().any(|f| f.file.is_some()) => new_ok_res(StatusCode::OK, uploaded),
+        Some((status_code, response_msg)) => Ok((
+            status_code,
+            Json(ResultBody { success: false, error: Some(response_msg.into()), data: Some(uploaded) }),
+        )),
+    }
+}
+
+/// Download a file
+///
+/// Supports single byte ranges with `Range` and `If-Range`, and conditional requests with `If-None-Match`.
+/// The `ETag` of a file is its hash.
+///
+/// Images, audio, video, PDFs and plain text can be shown in the browser with `?disposition=inline`.
+/// Other types, HTML and SVG included, are always sent as attachments
+#[utoipa::path(
+    get, path = "/dl/{file_hash}",
+    params(
+        ("disposition" = Option<String>, Query, description = "How the browser should handle the file. v can be one of: `attachment`, `inline`. Defaults to `attachment`."),
+    ),
+    responses(
+        (status = 200, description = "File has been successfully sent", body = Vec<u8>, content_type = "*/*"),
+        (status = 206, description = "Requested range of the file has been successfully sent", body = Vec<u8>, content_type = "*/*"),
+        (status = 304, description = "File matches the `If-None-Match` header"),
+        (status = 416, description = "Requested range is outside of the file"),
+        ExRes400, ExRes401, ExRes404, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state, headers), err(level = tracing::Level::DEBUG))]
+async fn files_dl_get(
+    State(state): State<AppState>,
+    Path(file_hash): Path<String>,
+    Extension(user_id): Extension<i32>,
+    Query(query): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ResError> {
+
+    download_file(&state, user_id, file_hash, &headers, query.inline()?).await
+}
+
+/// Streams the file into the response, taking the range and conditional headers into account. Also used by the public links
+/// Makes sure that the user owns the file. The Data service can only tell that by starting a download,
+/// so the stream gets dropped right after its first message, which cancels the rest of it
+async fn check_file_owner(state: &AppState, user_id: i32, file_hash: String) -> Result<(), ResError> {
+    let mut state = state.clone();
+
+    let mut stream = call_grpc_service(
+        DownloadFileReq { user_id, file_hash },
+        |req| state.files_client.download_file(req),
+        &state.data_token,
+        "files.download_file",
+    ).await?;
+
+    stream.next().await
+        .ok_or(ResError::ServerError("Could not get the first message from a file stream".into()))??;
+
+    Ok(())
+}
+
+pub async fn download_file(state: &AppState, user_id: i32, file_hash: String, headers: &HeaderMap, inline: bool) -> Result<Response, ResError> {
+    let mut state = state.clone();
+    let etag = format!("\"{file_hash}\"");
+
+    // the download has to be started even for conditional requests, since that's how the ownership of the file gets checked
+
+    let mut stream = call_grpc_service(
+        DownloadFileReq { user_id, file_hash },
+        |req| state.files_client.download_file(req),
+        &state.data_token,
+        "files.download_file",
+    ).await?;
+
+    let first_part = stream.next().await
+        .ok_or(ResError::ServerError("Could not get the first message from a file stream".into()))??;
+
+    let Some(DownloadFileMetadata {
+        name: file_name,
+        size: file_size,
+    }) = first_part.metadata else {
+        return Err(ResError::ServerError("Could not get metadata from the first message in a file stream".into()));
+    };
+
+    let file_size = u64::try_from(file_size)
+        .map_err(|_| ResError::ServerError(format!("Got an invalid file size from a file stream: {file_size}")))?;
+
+    let header_str = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let accept_ranges = (header::ACCEPT_RANGES, "bytes".to_string());
+    let etag_header = (header::ETAG, etag.clone());
+
+    if header_str(header::IF_NONE_MATCH).is_some_and(|v| etag_matches(v, &etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, [accept_ranges, etag_header]).into_response());
+    }
+
+    // the range gets ignored if the client's copy of the file is outdated.
+    // file hashes don't change, so this can only happen if the client made a mistake
+
+    let range = match (header_str(header::RANGE), header_str(header::IF_RANGE)) {
+        (Some(_), Some(if_range)) if if_range.trim() != etag => RangeResult::Full,
+        (Some(range), _) => parse_range(range, file_size),
+        (None, _) => RangeResult::Full,
+    };
+
+    // the stream ends early if the service is shutting down and the download takes too long
+
+    let stream = stream.take_until(state.shutdown.stream_token().cancelled_owned());
+
+    let (status, content_length, content_range, body) = match range {
+        RangeResult::Full => (
+            StatusCode::OK,
+            file_size,
+            None,
+            Body::from_stream(stream.inspect(|part| {
+                if let Ok(part) = part {
+                    telemetry::record_downloaded_bytes(part.data.len());
+                }
+            })),
+        ),
+        RangeResult::Partial(range) => (
+            StatusCode::PARTIAL_CONTENT,
+            range.len(),
+            Some(format!("bytes {}-{}/{file_size}", range.start, range.end)),
+            Body::from_stream(slice_stream(stream, range).inspect(|part| {
+                if let Ok(part) = part {
+                    telemetry::record_downloaded_bytes(part.len());
+                }
+            })),
+        ),
+        RangeResult::Unsatisfiable => return Ok((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [accept_ranges, etag_header, (header::CONTENT_RANGE, format!("bytes */{file_size}"))],
+        ).into_response()),
+    };
+
+    // the browser must not guess a different type than the one that was sent, since it could turn a file into a page.
+    // https://stackoverflow.com/a/28652339
+
+    let content_type = content_type(&file_name);
+    let content_disposition = content_disposition(&file_name, &content_type, inline);
+
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_LENGTH, content_length.to_string()),
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, content_disposition),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            accept_ranges,
+            etag_header,
+        ],
+        body,
+    ).into_response();
+
+    if let Some(content_range) = content_range {
+        response.headers_mut().insert(header::CONTENT_RANGE, content_range.parse()?);
+    }
+
+    Ok(response)
+}
+
+/// Get a thumbnail of an image
+///
+/// Works for PNG, JPEG, WebP and GIF files. The image gets scaled down to fit into `w` by `h` while keeping its aspect ratio,
+/// and is sent as WebP if it has transparency, or as JPEG otherwise. The sizes get rounded up to 32, 64, 128, 256, 512 or 1024,
+/// and the thumbnails get cached, so only the first request for each size is slow
+#[utoipa::path(
+    get, path = "/dl/{file_hash}/thumb",
+    params(
+        ("w" = Option<u32>, Query, description = "Max width of the thumbnail. v > 0 && v <= 1024. Defaults to `h`, or to 256 if both are missing."),
+        ("h" = Option<u32>, Query, description = "Max height of the thumbnail. v > 0 && v <= 1024. Defaults to `w`, or to 256 if both are missing."),
+    ),
+    responses(
+        (status = 200, description = "Thumbnail has been successfully sent", body = Vec<u8>, content_type = "image/jpeg"),
+        (status = 304, description = "Thumbnail matches the `If-None-Match` header"),
+        ExRes400, ExRes401, ExRes404, ExRes422, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state, headers), err(level = tracing::Level::DEBUG))]
+async fn files_thumb_get(
+    State(state): State<AppState>,
+    Path(file_hash): Path<String>,
+    Extension(user_id): Extension<i32>,
+    Query(query): Query<ThumbnailQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ResError> {
+
+    let (width, height) = query.dimensions()?;
+
+    if !ThumbnailCache::is_valid_hash(&file_hash) {
+        return Err(ResError::NotFound(format!("Could not find file {file_hash}")));
+    }
+
+    let etag = format!("\"{file_hash}-{width}x{height}\"");
+
+    if headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()).is_some_and(|v| etag_matches(v, &etag)) {
+        check_file_owner(&state, user_id, file_hash).await?;
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    let cache = &state.thumbnail_cache;
+
+    let (thumbnail, format) = match cache.get(&file_hash, width, height).await {
+        Some(cached) => {
+            check_file_owner(&state, user_id, file_hash).await?;
+            cached
+        },
+        None => {
+            let original = download_file(&state, user_id, file_hash.clone(), &HeaderMap::new(), false).await?;
+
+            check_source(original.headers(), cache.max_input_size)?;
+
+            let data = read_source(original.into_body(), cache.max_input_size).await?;
+
+            let (thumbnail, format) = tokio::task::spawn_blocking(move || generate(data, width, height)).await
+                .map_err(|e| ResError::ServerError(e.to_string()))??;
+
+            if let Err(e) = cache.put(&file_hash, width, height, format, &thumbnail).await {
+                tracing::warn!("Could not cache a thumbnail: {e}");
+            }
+
+            (thumbnail, format)
+        },
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (header::CACHE_CONTROL, "private, max-age=604800, immutable".to_string()),
+            (header::ETAG, etag),
+        ],
+        thumbnail,
+    ).into_response())
+}
+
+/// Follow an upload
+///
+/// Server-Sent Events stream with the progress of the `/files` upload that has the same `upload_id`.
+/// It can be opened before the upload starts, for up to 32 uploads at a time. Each `progress` event contains the whole current state of the upload,
+/// and the stream ends with a `done` event that also contains the result of each file
+#[utoipa::path(
+    get, path = "/progress/{upload_id}",
+    responses(
+        (status = 200, description = "Stream of `progress` events, followed by a `done` event", body = UploadProgress, content_type = "text/event-stream"),
+        ExRes400, ExRes401, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+async fn files_progress_get(
+    State(state): State<AppState>,
+    Path(upload_id): Path<String>,
+    Extension(user_id): Extension<i32>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ResError> {
+
+    let mut rx = state.upload_progress.subscribe(user_id, &upload_id)?;
+
+    // updates that come in faster than they get sent are merged together
+
+    let events = async_stream::stream! {
+        loop {
+            let progress = rx.borrow_and_update().clone();
+
+            let event = match progress.done {
+                true => "done",
+                false => "progress",
+            };
+
+            yield Event::default().event(event).json_data(&progress);
+
+            if progress.done || rx.changed().await.is_err() {
+                break;
+            }
+        }
+    };
+
+    // the stream would otherwise keep the service from shutting down until the grace period is over
+
+    let events = events.take_until(state.shutdown.stream_token().cancelled_owned());
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Get the storage usage
+///
+/// Returns the total size and amount of the user's files, along with the user's quota
+#[utoipa::path(
+    get, path = "/usage",
+    responses(
+        (status = 200, description = "Success", body = Usage),
+        ExRes401, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+async fn files_usage_get(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<i32>,
+) -> ServerResult<Usage> {
+
+    let usage = get_usage(&state, user_id).await?;
+
+    new_ok_res(StatusCode::OK, usage)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateLinkReq {
+    /// How long (in seconds) the link stays valid. Defaults to and is capped by the service's `MAX_LINK_TTL`
+    ttl: Option<u64>,
+    /// How many times the file can be downloaded through the link. Every successful response counts, including the ones for a range of the file
+    max_downloads: Option<u32>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PublicLink {
+    url: String,
+    /// Unix timestamp (in seconds)
+    expires: u64,
+    max_downloads: Option<u32>,
+}
+
+/// Create a public link
+///
+/// Creates a signed link that lets anyone download the file without logging in, until the link expires
+#[utoipa::path(
+    post, path = "/{file_id}/link",
+    request_body = CreateLinkReq,
+    responses(
+        (status = 201, description = "Link has been successfully created", body = PublicLink),
+        ExRes400, ExRes401, ExRes404, ExRes415, ExRes422, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+async fn files_link_post(
+    State(state): State<AppState>,
+    Path(file_id): Path<i32>,
+    Extension(user_id): Extension<i32>,
+    Json(body): Json<CreateLinkReq>,
+) -> ServerResult<PublicLink> {
+
+    if body.ttl == Some(0) || body.max_downloads == Some(0) {
+        return Err(ResError::InvalidValues("ttl and max_downloads must be greater than 0".into()));
+    }
+
+    // links are bound to the file hash, since that's what the downloads use
+
+    let file = find_file(&state, user_id, file_id).await?
+        .ok_or(ResError::NotFound(format!("Could not find file {file_id}")))?;
+
+    let claims = LinkClaims {
+        user_id,
+        file_hash: file.hash,
+        expires: unix_now() + body.ttl.unwrap_or(state.max_link_ttl).min(state.max_link_ttl),
+        max_downloads: body.max_downloads,
+        link_id: LinkSigner::new_link_id(),
+    };
+
+    let token = state.link_signer.sign(&claims)?;
+
+    new_ok_res(StatusCode::CREATED, PublicLink {
+        url: format!("{}/public/dl/{token}", state.public_url.trim_end_matches('/')),
+        expires: claims.expires,
+        max_downloads: claims.max_downloads,
+    })
+}
+
+/// Delete a file
+#[utoipa::path(
+    delete, path = "/{file_id}",
+    responses(
+        (status = 200, description = "File has been successfully deleted", body = Empty),
+        ExRes400, ExRes401, ExRes404, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+async fn files_delete(
+    State(mut state): State<AppState>,
+    Path(file_id): Path<i32>,
+    Extension(user_id): Extension<i32>,
+) -> ServerResult<Empty> {
+
+    let res_body = call_grpc_service(
+        DeleteFileReq { id: file_id, user_id },
+        |req| state.files_client.delete_file(req),
+        &state.data_token,
+        "files.delete_file",
+    ).await?;
+
+    state.quota_tracker.invalidate(user_id);
+    state.events.publish(user_id, ChangeEvent::FileDeleted { id: file_id });
+
+    new_ok_res(StatusCode::OK, res_body)
+}
@@ -0,0 +1,207 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::listing::list_files;
+use crate::error::ResError;
+use crate::types::AppState;
+
+/// User's current storage usage along with the limits. A limit of `None` means that there is no limit
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct Usage {
+    /// Total size of the user's files (in bytes)
+    pub bytes: u64,
+    pub files: u64,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+impl Usage {
+    /// Makes sure that one more file of `file_size` bytes fits into the quota
+    fn check(&self, file_size: u64) -> Result<(), ResError> {
+        if self.max_files.is_some_and(|max| self.files + 1 > max) {
+            return Err(ResError::QuotaExceeded(format!("The user already has {} out of {:?} files", self.files, self.max_files)));
+        }
+
+        if self.max_bytes.is_some_and(|max| self.bytes + file_size > max) {
+            return Err(ResError::QuotaExceeded(format!("A file of {file_size} bytes does not fit, the user already has {} out of {:?} bytes", self.bytes, self.max_bytes)));
+        }
+
+        Ok(())
+    }
+}
+
+pub fn quotas_enabled(state: &AppState) -> bool {
+    state.user_storage_quota > 0 || state.user_file_quota > 0
+}
+
+pub async fn get_usage(state: &AppState, user_id: i32) -> Result<Usage, ResError> {
+    let files = list_files(state, user_id).await?;
+
+    Ok(Usage {
+        bytes: files.iter().map(|f| f.size.max(0) as u64).sum(),
+        files: files.len() as u64,
+        max_bytes: Some(1024 * 1024 * state.user_storage_quota).filter(|v| *v > 0),
+        max_files: Some(state.user_file_quota).filter(|v| *v > 0),
+    })
+}
+
+/// How long the usage from the Data service is trusted for. Files only get removed through the gateway, which drops the usage right away,
+/// so this mostly matters for the changes that have been made through other instances
+const USAGE_TTL: Duration = Duration::from_secs(600);
+
+/// Keeps track of the uploads that are going on, so that concurrent uploads of the same user can't all pass the quota check
+/// with the same usage. Both multipart and tus uploads reserve their files here, and staged tus uploads count toward the usage as well.
+///
+/// The Data service can only tell the usage by going through all of the user's notes, so the usage gets kept for `USAGE_TTL`,
+/// and gets kept up to date with the files that the gateway creates. Removing files drops it, so that it gets fetched again on the next upload
+#[derive(Debug, Default)]
+pub struct QuotaTracker {
+    users: Mutex<HashMap<i32, Arc<UserQuota>>>,
+    usage: Mutex<HashMap<i32, (Usage, Instant)>>,
+}
+
+#[derive(Debug, Default)]
+struct UserQuota {
+    /// Held while checking the quota and while counting in the created files, so that a check can't miss a file
+    /// that has been created after the usage was fetched, but before the file's reservation was released
+    check: tokio::sync::Mutex<()>,
+    inner: Mutex<QuotaState>,
+}
+
+#[derive(Debug, Default)]
+struct QuotaState {
+    reserved_bytes: u64,
+    reserved_files: u64,
+}
+
+impl QuotaTracker {
+    /// Starts checking the user's uploads. Returns `None` if quotas are disabled
+    pub fn session(self: &Arc<Self>, state: &AppState, user_id: i32) -> Option<QuotaSession> {
+        if !quotas_enabled(state) {
+            return None;
+        }
+
+        let quota = self.users.lock().unwrap().entry(user_id).or_default().clone();
+        Some(QuotaSession { tracker: self.clone(), user_id, quota })
+    }
+
+    /// Makes the next upload fetch the usage again. Has to be called whenever the user's files get removed
+    pub fn invalidate(&self, user_id: i32) {
+        self.usage.lock().unwrap().remove(&user_id);
+    }
+
+    fn cached_usage(&self, user_id: i32) -> Option<Usage> {
+        self.usage.lock().unwrap()
+            .get(&user_id)
+            .filter(|(_, fetched)| fetched.elapsed() < USAGE_TTL)
+            .map(|(usage, _)| *usage)
+    }
+
+    fn store_usage(&self, user_id: i32, usage: Usage) {
+        let mut cache = self.usage.lock().unwrap();
+        cache.retain(|_, (_, fetched)| fetched.elapsed() < USAGE_TTL);
+        cache.insert(user_id, (usage, Instant::now()));
+    }
+
+    fn add_file(&self, user_id: i32, file_size: u64) {
+        if let Some((usage, _)) = self.usage.lock().unwrap().get_mut(&user_id) {
+            usage.bytes += file_size;
+            usage.files += 1;
+        }
+    }
+}
+
+/// Quota checks of a single request. The user's reservations are kept for as long as any of the user's sessions are alive
+#[derive(Debug)]
+pub struct QuotaSession {
+    tracker: Arc<QuotaTracker>,
+    user_id: i32,
+    quota: Arc<UserQuota>,
+}
+
+impl QuotaSession {
+    /// Makes sure that one more file of `file_size` bytes fits into the quota, and reserves the space for it.
+    /// `staged_id` is the tus upload that the file is being made of, since it's already counted in as a staged upload
+    pub async fn reserve(&self, state: &AppState, file_size: u64, staged_id: Option<&str>) -> Result<QuotaReservation<'_>, ResError> {
+        let _check = self.quota.check.lock().await;
+
+        let usage = match self.tracker.cached_usage(self.user_id) {
+            Some(usage) => usage,
+            None => {
+                let usage = get_usage(state, self.user_id).await?;
+                self.tracker.store_usage(self.user_id, usage);
+                usage
+            },
+        };
+
+        let (staged_bytes, staged_files) = state.upload_store.staged_usage(self.user_id, staged_id);
+
+        let mut inner = self.quota.inner.lock().unwrap();
+
+        Usage {
+            bytes: usage.bytes + staged_bytes + inner.reserved_bytes,
+            files: usage.files + staged_files + inner.reserved_files,
+            ..usage
+        }.check(file_size)?;
+
+        inner.reserved_bytes += file_size;
+        inner.reserved_files += 1;
+
+        Ok(QuotaReservation { session: self, file_size, released: false })
+    }
+}
+
+impl Drop for QuotaSession {
+    fn drop(&mut self) {
+        let mut users = self.tracker.users.lock().unwrap();
+
+        // the map holds one reference and this session holds another, so nobody else has any reservations
+        if Arc::strong_count(&self.quota) == 2 {
+            users.remove(&self.user_id);
+        }
+    }
+}
+
+/// Space reserved for a single file. If the reservation gets dropped, the file is assumed to have failed, and the space is freed up
+#[derive(Debug)]
+pub struct QuotaReservation<'a> {
+    session: &'a QuotaSession,
+    file_size: u64,
+    released: bool,
+}
+
+impl QuotaReservation<'_> {
+    fn release(&mut self, inner: &mut QuotaState) {
+        inner.reserved_bytes = inner.reserved_bytes.saturating_sub(self.file_size);
+        inner.reserved_files = inner.reserved_files.saturating_sub(1);
+        self.released = true;
+    }
+
+    /// Counts the file in, now that the Data service has it
+    pub async fn file_created(mut self) {
+        let session = self.session;
+        let _check = session.quota.check.lock().await;
+
+        self.release(&mut session.quota.inner.lock().unwrap());
+        session.tracker.add_file(session.user_id, self.file_size);
+    }
+
+    /// Frees up the reservation of a tus upload, now that it's on the disk and counts in as a staged upload
+    pub async fn upload_staged(mut self) {
+        let quota = &self.session.quota;
+        let _check = quota.check.lock().await;
+
+        self.release(&mut quota.inner.lock().unwrap());
+    }
+}
+
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            let quota = &self.session.quota;
+            self.release(&mut quota.inner.lock().unwrap());
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::pin::pin;
+
+use axum::body::Bytes;
+use futures_util::{Stream, StreamExt};
+
+/// Byte range that has been resolved against the file size. Inclusive on both ends, same as in the `Content-Range` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeResult {
+    /// No range, or a range that should be ignored, in which case the whole file gets sent
+    Full,
+    Partial(ByteRange),
+    /// The range is valid, but it's outside of the file
+    Unsatisfiable,
+}
+
+/// Parses the `Range` header value. Only single ranges are supported, and anything else gets ignored as allowed by RFC 9110
+pub fn parse_range(value: &str, size: u64) -> RangeResult {
+    let Some(range) = value.trim().strip_prefix("bytes=") else {
+        return RangeResult::Full;
+    };
+
+    if range.contains(',') {
+        return RangeResult::Full;
+    }
+
+    let Some((start, end)) = range.trim().split_once('-') else {
+        return RangeResult::Full;
+    };
+
+    match (start.parse::<u64>().ok(), end.parse::<u64>().ok(), start.is_empty(), end.is_empty()) {
+
+        // bytes=-500 (the last 500 bytes)
+        (None, Some(suffix), true, false) => match suffix {
+            0 => RangeResult::Unsatisfiable,
+            _ if size == 0 => RangeResult::Unsatisfiable,
+            _ => RangeResult::Partial(ByteRange { start: size.saturating_sub(suffix), end: size - 1 }),
+        },
+
+        // bytes=500- (everything starting from 500)
+        (Some(start), None, false, true) => match start < size {
+            true => RangeResult::Partial(ByteRange { start, end: size - 1 }),
+            false => RangeResult::Unsatisfiable,
+        },
+
+        // bytes=500-999
+        (Some(start), Some(end), false, false) if start <= end => match start < size {
+            true => RangeResult::Partial(ByteRange { start, end: end.min(size - 1) }),
+            false => RangeResult::Unsatisfiable,
+        },
+
+        _ => RangeResult::Full,
+    }
+}
+
+/// Checks if any of the entity tags in `If-None-Match` or `If-Range` match the `etag`. Weak tags are compared as if they were strong
+pub fn etag_matches(value: &str, etag: &str) -> bool {
+    value
+        .split(',')
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v.trim_start_matches("W/") == etag)
+}
+
+/// Skips the bytes before the range and stops reading the stream once the range is over
+pub fn slice_stream<S, B, E>(stream: S, range: ByteRange) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: Into<Bytes>,
+{
+    async_stream::stream! {
+        let mut stream = pin!(stream);
+        let mut chunk_start = 0u64;
+
+        while let Some(part) = stream.next().await {
+            let data: Bytes = match part {
+                Ok(p) => p.into(),
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                },
+            };
+
+            let chunk_end = chunk_start + data.len() as u64;
+
+            if chunk_end > range.start {
+                let from = range.start.saturating_sub(chunk_start) as usize;
+                let to = (range.end + 1).min(chunk_end) - chunk_start;
+                yield Ok(data.slice(from..to as usize));
+            }
+
+            chunk_start = chunk_end;
+
+            if chunk_start > range.end {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(start: u64, end: u64) -> RangeResult {
+        RangeResult::Partial(ByteRange { start, end })
+    }
+
+    #[test]
+    fn suffix_range() {
+        assert_eq!(partial(900, 999), parse_range("bytes=-100", 1000));
+        assert_eq!(partial(0, 999), parse_range("bytes=-5000", 1000));
+        assert_eq!(RangeResult::Unsatisfiable, parse_range("bytes=-0", 1000));
+        assert_eq!(RangeResult::Unsatisfiable, parse_range("bytes=-100", 0));
+    }
+
+    #[test]
+    fn open_ended_range() {
+        assert_eq!(partial(500, 999), parse_range("bytes=500-", 1000));
+        assert_eq!(partial(999, 999), parse_range("bytes=999-", 1000));
+    }
+
+    #[test]
+    fn closed_range() {
+        assert_eq!(partial(0, 0), parse_range("bytes=0-0", 1000));
+        assert_eq!(partial(100, 199), parse_range(" bytes=100-199 ", 1000));
+        assert_eq!(partial(500, 999), parse_range("bytes=500-5000", 1000));
+    }
+
+    #[test]
+    fn out_of_bounds_unsatisfiable() {
+        assert_eq!(RangeResult::Unsatisfiable, parse_range("bytes=1000-", 1000));
+        assert_eq!(RangeResult::Unsatisfiable, parse_range("bytes=1000-1999", 1000));
+        assert_eq!(RangeResult::Unsatisfiable, parse_range("bytes=0-", 0));
+    }
+
+    #[test]
+    fn multi_range_ignored() {
+        assert_eq!(RangeResult::Full, parse_range("bytes=0-99,200-299", 1000));
+    }
+
+    #[test]
+    fn malformed_ignored() {
+        for value in ["", "0-99", "items=0-99", "bytes=", "bytes=-", "bytes=abc-def", "bytes=200-100", "bytes=1-2-3", "bytes=--5"] {
+            assert_eq!(RangeResult::Full, parse_range(value, 1000), "{value}");
+        }
+    }
+
+    #[test]
+    fn etags_compared() {
+        assert!(etag_matches("\"a\", W/\"b\"", "\"b\""));
+        assert!(etag_matches("*", "\"b\""));
+        assert!(!etag_matches("\"a\"", "\"b\""));
+    }
+
+    /// Slices `chunks` and returns the result as a single string
+    async fn slice(chunks: &[&'static str], start: u64, end: u64) -> String {
+        let stream = futures_util::stream::iter(chunks.iter().map(|c| Ok::<_, ()>(Bytes::from_static(c.as_bytes()))));
+
+        let parts: Vec<Bytes> = slice_stream(stream, ByteRange { start, end })
+            .map(|p| p.unwrap())
+            .collect()
+            .await;
+
+        parts.iter().map(|p| std::str::from_utf8(p).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn slice_within_chunk() {
+        assert_eq!("cd", slice(&["abcdef", "ghi"], 2, 3).await);
+    }
+
+    #[tokio::test]
+    async fn slice_across_chunks() {
+        assert_eq!("efghij", slice(&["abcdef", "ghi", "jkl"], 4, 9).await);
+    }
+
+    #[tokio::test]
+    async fn slice_on_chunk_boundaries() {
+        assert_eq!("ghi", slice(&["abc", "def", "ghi", "jkl"], 6, 8).await);
+        assert_eq!("a", slice(&["abc", "def"], 0, 0).await);
+        assert_eq!("f", slice(&["abc", "def"], 5, 5).await);
+    }
+
+    #[tokio::test]
+    async fn slice_past_the_end() {
+        assert_eq!("def", slice(&["abc", "def"], 3, 100).await);
+    }
+
+    #[tokio::test]
+    async fn slice_stops_reading() {
+        let stream = futures_util::stream::iter([Ok(Bytes::from_static(b"abc")), Err("should not be read")]);
+        let parts: Vec<_> = slice_stream(stream, ByteRange { start: 0, end: 2 }).collect().await;
+
+        assert_eq!(vec![Ok(Bytes::from_static(b"abc"))], parts);
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::Cursor;
+
+use axum::body::{Body, Bytes};
+use axum::http::header;
+use futures_util::StreamExt;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use serde::Deserialize;
+
+use crate::error::ResError;
+use crate::thumbnail_cache::ThumbnailFormat;
+
+/// Max width and height of a thumbnail (in pixels)
+pub const MAX_DIMENSION: u32 = 1024;
+const DEFAULT_DIMENSION: u32 = 256;
+/// Dimensions that the requested ones get rounded up to, so that each image can only have a few cached thumbnails
+const DIMENSIONS: [u32; 6] = [32, 64, 128, 256, 512, MAX_DIMENSION];
+
+/// Max width and height of an image that can be decoded, to keep small files from expanding into huge images
+const MAX_INPUT_DIMENSION: u32 = 16384;
+const MAX_DECODE_ALLOC: u64 = 512 * 1024 * 1024;
+
+const SUPPORTED_TYPES: &[&str] = &["image/png", "image/jpeg", "image/webp", "image/gif"];
+
+/// helper struct that the thumbnail url query deserializes into
+#[derive(Debug, Default, Deserialize)]
+pub struct ThumbnailQuery {
+    w: Option<String>,
+    h: Option<String>,
+}
+
+impl ThumbnailQuery {
+    /// Returns the requested width and height, rounded up to the closest of `DIMENSIONS`. A missing dimension defaults to the other one
+    pub fn dimensions(&self) -> Result<(u32, u32), ResError> {
+        let parse = |value: &Option<String>| match value.as_deref() {
+            None => Ok(None),
+            Some(v) => match v.parse() {
+                Ok(v) if v > 0 && v <= MAX_DIMENSION => Ok(DIMENSIONS.into_iter().find(|&d| d >= v)),
+                _ => Err(ResError::InvalidFields(format!("Received invalid query field: {v}"))),
+            },
+        };
+
+        Ok(match (parse(&self.w)?, parse(&self.h)?) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(d), None) | (None, Some(d)) => (d, d),
+            (None, None) => (DEFAULT_DIMENSION, DEFAULT_DIMENSION),
+        })
+    }
+}
+
+/// Makes sure that the downloaded file is an image that a thumbnail can be made of, judging by its response headers
+pub fn check_source(headers: &axum::http::HeaderMap, max_input_size: u64) -> Result<(), ResError> {
+    let header_str = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let content_type = header_str(header::CONTENT_TYPE).unwrap_or_default();
+    if !SUPPORTED_TYPES.contains(&content_type) {
+        return Err(ResError::InvalidValues(format!("Can't make a thumbnail of a file with the type {content_type}")));
+    }
+
+    let size: u64 = header_str(header::CONTENT_LENGTH).and_then(|v| v.parse().ok()).unwrap_or_default();
+    if size > max_input_size {
+        return Err(too_big(size, max_input_size));
+    }
+
+    Ok(())
+}
+
+fn too_big(size: u64, max_input_size: u64) -> ResError {
+    ResError::InvalidValues(format!("The file is at least {size} bytes, which is over the thumbnail limit of {max_input_size} bytes"))
+}
+
+/// Downloads the whole image. Files without a `Content-Length` only turn out to be too big while being read,
+/// in which case they get the same error as from `check_source`
+pub async fn read_source(body: Body, max_input_size: u64) -> Result<Bytes, ResError> {
+    let mut stream = body.into_data_stream();
+    let mut data = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| ResError::ServerError(format!("Could not download the original image: {e}")))?;
+        data.extend_from_slice(&chunk);
+
+        if data.len() as u64 > max_input_size {
+            return Err(too_big(data.len() as u64, max_input_size));
+        }
+    }
+
+    Ok(data.into())
+}
+
+/// Decodes the image and scales it down to fit into `width` by `height`, keeping the aspect ratio.
+/// Images that already fit don't get scaled up. This is CPU heavy, so it should run on a blocking thread
+pub fn generate(data: Bytes, width: u32, height: u32) -> Result<(Vec<u8>, ThumbnailFormat), ResError> {
+    let invalid = |e: image::ImageError| ResError::InvalidValues(format!("Could not decode the image: {e}"));
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_INPUT_DIMENSION);
+    limits.max_image_height = Some(MAX_INPUT_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| ResError::ServerError(e.to_string()))?;
+
+    reader.limits(limits);
+
+    let image = reader.decode().map_err(invalid)?;
+
+    let image = match image.width() > width || image.height() > height {
+        true => image.thumbnail(width, height),
+        false => image,
+    };
+
+    let (image, format, image_format) = match image.color().has_alpha() {
+        true => (DynamicImage::ImageRgba8(image.to_rgba8()), ThumbnailFormat::WebP, ImageFormat::WebP),
+        false => (DynamicImage::ImageRgb8(image.to_rgb8()), ThumbnailFormat::Jpeg, ImageFormat::Jpeg),
+    };
+
+    let mut output = Cursor::new(Vec::new());
+    image.write_to(&mut output, image_format).map_err(|e| ResError::ServerError(format!("Could not encode a thumbnail: {e}")))?;
+
+    Ok((output.into_inner(), format))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{RgbImage, RgbaImage};
+
+    use super::*;
+
+    fn encode_png(image: DynamicImage) -> Bytes {
+        let mut output = Cursor::new(Vec::new());
+        image.write_to(&mut output, ImageFormat::Png).unwrap();
+        output.into_inner().into()
+    }
+
+    fn decode(data: &[u8]) -> (ImageFormat, u32, u32) {
+        let format = image::guess_format(data).unwrap();
+        let image = image::load_from_memory(data).unwrap();
+        (format, image.width(), image.height())
+    }
+
+    #[test]
+    fn opaque_png_becomes_jpeg() {
+        let png = encode_png(DynamicImage::ImageRgb8(RgbImage::new(64, 32)));
+        let (thumbnail, format) = generate(png, 16, 16).unwrap();
+
+        assert!(matches!(format, ThumbnailFormat::Jpeg));
+        assert_eq!((ImageFormat::Jpeg, 16, 8), decode(&thumbnail));
+    }
+
+    #[test]
+    fn transparent_png_becomes_webp() {
+        let png = encode_png(DynamicImage::ImageRgba8(RgbaImage::new(32, 64)));
+        let (thumbnail, format) = generate(png, 16, 16).unwrap();
+
+        assert!(matches!(format, ThumbnailFormat::WebP));
+        assert_eq!((ImageFormat::WebP, 8, 16), decode(&thumbnail));
+    }
+
+    #[test]
+    fn small_image_not_scaled_up() {
+        let png = encode_png(DynamicImage::ImageRgb8(RgbImage::new(10, 5)));
+        let (thumbnail, _) = generate(png, 256, 256).unwrap();
+
+        assert_eq!((ImageFormat::Jpeg, 10, 5), decode(&thumbnail));
+    }
+
+    #[test]
+    fn invalid_image_rejected() {
+        assert!(matches!(generate(Bytes::from_static(b"not an image"), 16, 16), Err(ResError::InvalidValues(_))));
+    }
+
+    #[test]
+    fn dimensions_rounded_up() {
+        let query = |w: Option<&str>, h: Option<&str>| ThumbnailQuery { w: w.map(Into::into), h: h.map(Into::into) }.dimensions().ok();
+
+        assert_eq!(Some((32, 32)), query(Some("1"), None));
+        assert_eq!(Some((256, 512)), query(Some("200"), Some("257")));
+        assert_eq!(Some((1024, 1024)), query(None, Some("1024")));
+        assert_eq!(Some((256, 256)), query(None, None));
+        assert_eq!(None, query(Some("1025"), None));
+    }
+
+    #[tokio::test]
+    async fn source_without_length_over_limit() {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![Ok(Bytes::from(vec![0; 600])), Ok(Bytes::from(vec![0; 600]))];
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+
+        assert!(matches!(read_source(body, 1000).await, Err(ResError::InvalidValues(_))));
+        assert_eq!(1000, read_source(Body::from(vec![0; 1000]), 1000).await.unwrap().len());
+    }
+}
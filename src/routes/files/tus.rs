@@ -0,0 +1,404 @@
+use std::{pin::pin, time::SystemTime};
+
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, Path, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::map_response;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Extension, Router};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tower_http::limit::RequestBodyLimitLayer;
+
+use crate::proto::files::create_file_metadata::AttachId;
+use crate::proto::files::{CreateFileMetadata, File};
+use super::create_file;
+use crate::types::{new_err_res, AppState, ExRes400, ExRes401, ExRes404, ExRes415, ExRes5XX};
+use crate::upload_store::{Upload, UploadInfo};
+use crate::error::ResError;
+use crate::events::ChangeEvent;
+
+pub const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+pub const TUS_VERSION: HeaderName = HeaderName::from_static("tus-version");
+pub const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+pub const TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
+pub const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+pub const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+pub const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+pub const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+/// Not a part of the protocol. Gets sent along with the last chunk's response, since that's when the file gets created
+pub const UPLOAD_FILE_ID: HeaderName = HeaderName::from_static("upload-file-id");
+
+const VERSION: &str = "1.0.0";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Has no state of its own, since it gets nested into the files router
+pub fn get_router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/", post(tus_post).options(tus_options))
+        .route("/:id", axum::routing::head(tus_head).patch(tus_patch).delete(tus_delete))
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(
+            1024 * 1024 * state.req_body_limit,
+        ))
+        .layer(map_response(add_tus_resumable))
+}
+
+async fn add_tus_resumable(mut response: Response) -> Response {
+    response.headers_mut().insert(TUS_RESUMABLE, HeaderValue::from_static(VERSION));
+    response
+}
+
+fn get_header<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Clients that speak a different version of the protocol get rejected, as the spec requires
+fn version_mismatch(headers: &HeaderMap) -> Option<Response> {
+    match get_header(headers, &TUS_RESUMABLE) {
+        Some(VERSION) => None,
+        _ => Some((
+            [(TUS_VERSION, VERSION)],
+            new_err_res(StatusCode::PRECONDITION_FAILED, "unsupported tus version", format!("Expected {TUS_RESUMABLE} to be {VERSION}")),
+        ).into_response()),
+    }
+}
+
+fn parse_u64_header(headers: &HeaderMap, name: &HeaderName) -> Result<u64, ResError> {
+    get_header(headers, name)
+        .ok_or(ResError::InvalidFields(format!("Could not get the {name} header")))?
+        .parse()
+        .map_err(|_| ResError::InvalidFields(format!("Could not parse the {name} header")))
+}
+
+/// Parses `Upload-Metadata`, which is a comma separated list of keys with optional base64 encoded values
+fn parse_metadata(value: &str) -> Result<Vec<(&str, String)>, ResError> {
+    value
+        .split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = STANDARD.decode(value.trim())
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok())
+                .ok_or(ResError::InvalidValues(format!("Could not decode the {key} metadata value")))?;
+
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn expires_header(expires: SystemTime) -> (HeaderName, String) {
+    (UPLOAD_EXPIRES, httpdate::fmt_http_date(expires))
+}
+
+/// Get the tus configuration
+///
+/// Describes the supported tus version, extensions and max upload size
+#[utoipa::path(
+    options, path = "/tus",
+    responses(
+        (status = 204, description = "Success"),
+        ExRes401, ExRes5XX,
+    ),
+)]
+async fn tus_options(
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            (TUS_VERSION, VERSION.to_string()),
+            (TUS_EXTENSION, "creation,expiration,termination".to_string()),
+            (TUS_MAX_SIZE, state.upload_store.max_size.to_string()),
+        ],
+    )
+}
+
+/// Create a new resumable upload
+///
+/// Creates an upload according to the tus 1.0.0 `creation` extension. The `Upload-Metadata` header must contain `filename` and either `note_id` or `shelf_id`
+#[utoipa::path(
+    post, path = "/tus",
+    params(
+        ("Tus-Resumable" = String, Header, description = "Must be `1.0.0`"),
+        ("Upload-Length" = u64, Header, description = "Size of the whole file in bytes"),
+        ("Upload-Metadata" = String, Header, description = "Comma separated `key base64(value)` pairs"),
+    ),
+    responses(
+        (status = 201, description = "Upload has been created. Its url is in the `Location` header"),
+        (status = 412, description = "Unsupported tus version"),
+        (status = 413, description = "The file is too big"),
+        (status = 507, description = "The file does not fit into the user's quota"),
+        ExRes400, ExRes401, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state, headers), err(level = tracing::Level::DEBUG))]
+async fn tus_post(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<i32>,
+    headers: HeaderMap,
+) -> Result<Response, ResError> {
+
+    if let Some(res) = version_mismatch(&headers) {
+        return Ok(res);
+    }
+
+    if headers.contains_key("upload-defer-length") {
+        return Err(ResError::BadRequest("Deferred upload length is not supported".into()));
+    }
+
+    let length = parse_u64_header(&headers, &UPLOAD_LENGTH)?;
+
+    if length > state.upload_store.max_size {
+        return Ok(new_err_res(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "file is too big",
+            format!("Upload length {length} exceeds the max size of {}", state.upload_store.max_size),
+        ).into_response());
+    }
+
+    let metadata = parse_metadata(get_header(&headers, &UPLOAD_METADATA).unwrap_or_default())?;
+    let get_value = |key| metadata.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str());
+
+    let name = get_value("filename")
+        .ok_or(ResError::InvalidFields("Could not get the filename from the upload metadata".into()))?
+        .to_string();
+
+    let (note_id, shelf_id) = match (get_value("note_id"), get_value("shelf_id")) {
+        (Some(id), None) => (Some(id.parse()?), None),
+        (None, Some(id)) => (None, Some(id.parse()?)),
+        _ => return Err(ResError::InvalidFields("The upload metadata must contain exactly one of note_id and shelf_id".into())),
+    };
+
+    // from here on the upload counts toward the usage as a staged upload
+
+    let quota = state.quota_tracker.session(&state, user_id);
+    let reservation = match &quota {
+        Some(quota) => Some(quota.reserve(&state, length, None).await?),
+        None => None,
+    };
+
+    let id = state.upload_store.create(user_id, name, note_id, shelf_id, length).await?;
+
+    if let Some(reservation) = reservation {
+        reservation.upload_staged().await;
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        [
+            (header::LOCATION, format!("/files/tus/{id}")),
+            (UPLOAD_OFFSET, "0".to_string()),
+            expires_header(state.upload_store.expires_at(SystemTime::now())),
+        ],
+    ).into_response())
+}
+
+/// Get the upload offset
+///
+/// Returns the amount of bytes that have been received so far, so that the client knows where to resume from
+#[utoipa::path(
+    head, path = "/tus/{upload_id}",
+    responses(
+        (status = 200, description = "Success. The offset is in the `Upload-Offset` header"),
+        ExRes401, ExRes404, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+async fn tus_head(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(user_id): Extension<i32>,
+) -> Result<Response, ResError> {
+
+    let upload = state.upload_store.get(&id, user_id).await?;
+
+    Ok((
+        [
+            (UPLOAD_OFFSET, upload.offset.to_string()),
+            (UPLOAD_LENGTH, upload.info.length.to_string()),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+            expires_header(upload.expires),
+        ],
+    ).into_response())
+}
+
+/// Upload a chunk
+///
+/// Appends the body to the upload. Once the whole file has been received, it gets sent to the Data service
+/// and its id is returned in the `Upload-File-Id` header. If that fails, the last request can be retried with an empty body
+#[utoipa::path(
+    patch, path = "/tus/{upload_id}",
+    params(
+        ("Tus-Resumable" = String, Header, description = "Must be `1.0.0`"),
+        ("Upload-Offset" = u64, Header, description = "Must be equal to the current offset of the upload"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/offset+octet-stream"),
+    responses(
+        (status = 204, description = "Chunk has been received. The new offset is in the `Upload-Offset` header"),
+        (status = 409, description = "`Upload-Offset` does not match the current offset"),
+        (status = 412, description = "Unsupported tus version"),
+        (status = 507, description = "The whole file has been received, but it does not fit into the user's quota anymore"),
+        ExRes400, ExRes401, ExRes404, ExRes415, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state, headers, body), err(level = tracing::Level::DEBUG))]
+async fn tus_patch(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(user_id): Extension<i32>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, ResError> {
+
+    if let Some(res) = version_mismatch(&headers) {
+        return Ok(res);
+    }
+
+    if get_header(&headers, &header::CONTENT_TYPE) != Some(OFFSET_CONTENT_TYPE) {
+        return Err(ResError::InvalidContentType(format!("Expected the content type to be {OFFSET_CONTENT_TYPE}")));
+    }
+
+    // checking the owner before locking, so that other users can't tell whether the upload exists by getting 409.
+    // the offset has to be read again once locked, since another request might have been writing to it
+
+    state.upload_store.get(&id, user_id).await?;
+    let _lock = state.upload_store.lock(&id)?;
+    let Upload { info, mut offset, expires } = state.upload_store.get(&id, user_id).await?;
+
+    let client_offset = parse_u64_header(&headers, &UPLOAD_OFFSET)?;
+    if client_offset != offset {
+        return Err(ResError::Conflict(format!("Expected Upload-Offset to be {offset}, got {client_offset}")));
+    }
+
+    // writing the chunks as they come, so that everything that has been received is kept even if the connection breaks.
+    // the body also stops if the service is shutting down, and the client can resume after the restart
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(state.upload_store.data_path(&id))
+        .await?;
+
+    let mut body = pin!(body
+        .into_data_stream()
+        .take_until(state.shutdown.stream_token().cancelled_owned()));
+
+    let mut body_error = None;
+
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(c) => c,
+            Err(e) => {
+                body_error = Some(e);
+                break;
+            },
+        };
+
+        if offset + chunk.len() as u64 > info.length {
+            body_error = Some(axum::Error::new(format!("The upload exceeds its length of {}", info.length)));
+            break;
+        }
+
+        file.write_all(&chunk).await?;
+        offset += chunk.len() as u64;
+    }
+
+    file.flush().await?;
+    drop(file);
+
+    if let Some(e) = body_error {
+        return Err(ResError::BadRequest(format!("Could not receive the whole chunk, stopped at offset {offset}: {e}")));
+    }
+
+    let offset_header = (UPLOAD_OFFSET, offset.to_string());
+
+    if offset < info.length {
+        // receiving anything pushes the expiry back, since it goes by when the upload was last worked on
+
+        let expires = match offset > client_offset {
+            true => state.upload_store.expires_at(SystemTime::now()),
+            false => expires,
+        };
+
+        return Ok((StatusCode::NO_CONTENT, [offset_header, expires_header(expires)]).into_response());
+    }
+
+    // the file is complete, so it's time to send it to the Data service
+
+    let new_file = create_staged_file(&state, &info, &id).await?;
+
+    Ok((StatusCode::NO_CONTENT, [offset_header, (UPLOAD_FILE_ID, new_file.id.to_string())]).into_response())
+}
+
+/// Streams the staged upload into the usual `create_file` call, and removes the upload once it's done.
+/// The quota gets checked again, since other uploads might have taken up the space in the meantime
+async fn create_staged_file(state: &AppState, info: &UploadInfo, id: &str) -> Result<File, ResError> {
+    let attach_id = match (info.note_id, info.shelf_id) {
+        (Some(note_id), _) => AttachId::NoteId(note_id),
+        (None, Some(shelf_id)) => AttachId::ShelfId(shelf_id),
+        (None, None) => return Err(ResError::ServerError(format!("Upload {id} is not attached to anything"))),
+    };
+
+    let metadata = CreateFileMetadata {
+        user_id: info.user_id,
+        name: info.name.clone(),
+        attach_id: Some(attach_id),
+        file_size: info.length,
+    };
+
+    let quota = state.quota_tracker.session(state, info.user_id);
+    let reservation = match &quota {
+        Some(quota) => Some(quota.reserve(state, info.length, Some(id)).await?),
+        None => None,
+    };
+
+    let file = tokio::fs::File::open(state.upload_store.data_path(id)).await?;
+
+    let (new_file, _) = create_file(state, metadata, None, ReaderStream::new(file), |_| ()).await?;
+    state.upload_store.remove(id).await;
+
+    if let Some(reservation) = reservation {
+        reservation.file_created().await;
+    }
+
+    state.events.publish(info.user_id, ChangeEvent::file_created(new_file.clone(), attach_id));
+
+    Ok(new_file)
+}
+
+/// Cancel an upload
+///
+/// Removes the upload along with all of the data received so far, according to the tus `termination` extension
+#[utoipa::path(
+    delete, path = "/tus/{upload_id}",
+    responses(
+        (status = 204, description = "Upload has been removed"),
+        (status = 412, description = "Unsupported tus version"),
+        ExRes401, ExRes404, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state, headers), err(level = tracing::Level::DEBUG))]
+async fn tus_delete(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(user_id): Extension<i32>,
+    headers: HeaderMap,
+) -> Result<Response, ResError> {
+
+    if let Some(res) = version_mismatch(&headers) {
+        return Ok(res);
+    }
+
+    // checking the owner before locking, same as in `tus_patch`
+
+    state.upload_store.get(&id, user_id).await?;
+    let _lock = state.upload_store.lock(&id)?;
+    state.upload_store.remove(&id).await;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
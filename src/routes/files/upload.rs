@@ -0,0 +1,216 @@
+use std::pin::pin;
+
+use axum::body::Bytes;
+use axum::extract::multipart::Field;
+use axum::http::StatusCode;
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::debug;
+use utoipa::ToSchema;
+
+use crate::proto::files::{CreateFileMetadata, CreateFileReq, File};
+use crate::upload_store::TempFile;
+use crate::{error::ResError, telemetry, types::{call_grpc_service, AppState}};
+
+/// Result of uploading a single file from a multipart body
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UploadedFile {
+    pub name: String,
+    pub file: Option<File>,
+    /// Hex encoded SHA-256 of the received data
+    pub sha256: Option<String>,
+    pub error: Option<String>,
+    /// Status code of the error, used as the response's status code if none of the files got uploaded
+    #[serde(skip)]
+    pub error_status: Option<(StatusCode, &'static str)>,
+}
+
+impl UploadedFile {
+    pub fn new(name: String, result: Result<(File, String), ResError>) -> Self {
+        match result {
+            Ok((file, sha256)) => Self { name, file: Some(file), sha256: Some(sha256), error: None, error_status: None },
+            Err(e) => {
+                let (status_code, response_msg, internal_msg) = e.into_parts();
+                debug!(name, response_msg, internal_msg, "Could not upload a file");
+
+                Self { name, file: None, sha256: None, error: Some(response_msg.into()), error_status: Some((status_code, response_msg)) }
+            },
+        }
+    }
+}
+
+/// Streams `data` into a `create_file` call, regrouping it into chunks of `MAX_FILE_CHUNK_SIZE`.
+/// Also makes sure that the data matches the declared `file_size` and the optional hex encoded SHA-256 `checksum`.
+/// Returns the new file along with the data's hex encoded SHA-256. `on_chunk` gets called with the size of each chunk that the Data service takes in.
+///
+/// If the virus scanner is enabled, the data also gets sent to it along the way.
+///
+/// The last chunk is held back until the data is verified and scanned, so that the Data service can't finish the file before that.
+/// If the data fails in any way, the call gets dropped, which resets the stream, and the last chunk never gets sent
+pub async fn create_file<S, E, F>(state: &AppState, metadata: CreateFileMetadata, checksum: Option<String>, data: S, on_chunk: F) -> Result<(File, String), ResError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<ResError>,
+    F: Fn(usize),
+{
+    let mut state = state.clone();
+    let chunk_size = 1024 * 1024 * state.file_chunk_size;
+    let name = metadata.name.clone();
+    let file_size = metadata.file_size;
+
+    // the grpc stream has to be 'static, so the data gets passed to it through a channel
+
+    let (tx, mut rx) = mpsc::channel(1);
+
+    let file_stream = async_stream::stream! {
+        yield CreateFileReq { metadata: Some(metadata), data: Vec::new() };
+
+        while let Some(data) = rx.recv().await {
+            yield CreateFileReq { metadata: None, data };
+        }
+    };
+
+    let scanner = state.scanner.clone();
+
+    let send_chunks = async move {
+        let mut scan = scanner.start().await?;
+        let mut data = pin!(data);
+        let mut curr_chunk = Vec::with_capacity(chunk_size);
+        let mut held_chunk: Option<Vec<u8>> = None;
+        let mut hasher = Sha256::new();
+        let mut received = 0u64;
+        let mut i = 0;
+
+        loop {
+            let next_chunk = data.next().await.transpose().map_err(Into::into)?;
+
+            if let Some(next_chunk) = &next_chunk {
+                received += next_chunk.len() as u64;
+                hasher.update(next_chunk);
+
+                if received > file_size {
+                    return Err(ResError::InvalidValues(format!("Received more than the declared file_size of {file_size} bytes")));
+                }
+
+                if let Some(s) = scan.take() {
+                    scan = s.write(next_chunk).await?;
+                }
+            }
+
+            // sending the chunk once it's big enough, or once the data is over
+
+            let last = next_chunk.is_none();
+            let mut next_chunk = next_chunk.unwrap_or_default();
+
+            while !next_chunk.is_empty() || (last && !curr_chunk.is_empty()) {
+                let take = (chunk_size - curr_chunk.len()).min(next_chunk.len());
+                curr_chunk.extend_from_slice(&next_chunk.split_to(take));
+
+                if curr_chunk.len() < chunk_size && !last {
+                    continue;
+                }
+
+                let data = std::mem::replace(&mut curr_chunk, Vec::with_capacity(chunk_size));
+
+                i += 1;
+                if i < 10 || (i < 100 && i % 10 == 0) || (i < 1000 && i % 100 == 0) || i % 1000 == 0 {
+                    debug!(name, "chunk {}: {}", i, data.len());
+                }
+
+                if let Some(held_chunk) = held_chunk.replace(data) {
+                    let len = held_chunk.len();
+                    telemetry::record_uploaded_bytes(len);
+
+                    // the call has already ended, and its result will tell why
+                    if tx.send(held_chunk).await.is_err() {
+                        return Ok(None);
+                    }
+
+                    on_chunk(len);
+                }
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        let sha256 = format!("{:x}", hasher.finalize());
+
+        if received != file_size {
+            return Err(ResError::InvalidValues(format!("Received {received} bytes, but the declared file_size is {file_size}")));
+        }
+
+        if checksum.is_some_and(|c| !c.eq_ignore_ascii_case(&sha256)) {
+            return Err(ResError::InvalidValues(format!("The checksum does not match the received data's SHA-256 {sha256}")));
+        }
+
+        if let Some(scan) = scan {
+            scan.finish().await?;
+        }
+
+        if let Some(held_chunk) = held_chunk {
+            let len = held_chunk.len();
+            telemetry::record_uploaded_bytes(len);
+
+            if tx.send(held_chunk).await.is_err() {
+                return Ok(None);
+            }
+
+            on_chunk(len);
+        }
+
+        Ok::<_, ResError>(Some(sha256))
+    };
+
+    // if the service is shutting down and the upload takes too long,
+    // the call gets dropped, which cancels the grpc stream on the Data service's side as well
+
+    let cancelled = state.shutdown.stream_token();
+
+    let mut call = pin!(call_create_file(&mut state, file_stream));
+    let mut send_chunks = pin!(send_chunks);
+    let mut sha256 = None;
+
+    loop {
+        tokio::select! {
+            res = &mut call => return match sha256 {
+                Some(sha256) => Ok((res?, sha256)),
+                None => Err(res.err().unwrap_or(ResError::ServerError("The file was created before all of its data was sent".into()))),
+            },
+            res = &mut send_chunks, if sha256.is_none() => match res? {
+                Some(res) => sha256 = Some(res),
+                None => return Err(call.await.err().unwrap_or(ResError::ServerError("The Data service ended the upload early".into()))),
+            },
+            _ = cancelled.cancelled() => return Err(ResError::ServiceUnavailable("The upload was cancelled because the service is shutting down".into())),
+        }
+    }
+}
+
+async fn call_create_file(state: &mut AppState, file_stream: impl Stream<Item = CreateFileReq> + Send + 'static) -> Result<File, ResError> {
+    Ok(call_grpc_service(
+        file_stream,
+        |req| state.files_client.create_file(req),
+        &state.data_token,
+        "files.create_file",
+    ).await?)
+}
+
+/// Saves a multipart file into a temporary file, for when it can't be sent to the Data service right away. Returns the file along with its size
+pub async fn spool_field(state: &AppState, field: &mut Field<'_>) -> Result<(TempFile, u64), ResError> {
+    let temp_file = state.upload_store.temp_file();
+    let mut file = tokio::fs::File::create(&temp_file.path).await?;
+    let mut size = 0;
+
+    while let Some(chunk) = field.chunk().await? {
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+
+    file.flush().await?;
+
+    Ok((temp_file, size))
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+use serde::Serialize;
+use tonic::transport::Channel;
+use tonic_health::pb::{health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::types::{call_grpc_service, new_ok_res, AppState, ExRes5XX, Json, ResultBody, ServerResult};
+
+pub fn get_router(state: &AppState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz_get))
+        .route("/readyz", get(readyz_get))
+        .with_state(state.clone())
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(healthz_get, readyz_get),
+    components(schemas(Readiness, DependencyStatus)),
+)]
+pub struct Api;
+
+/// Readiness of each service that the gateway depends on
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    auth: DependencyStatus,
+    data: DependencyStatus,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DependencyStatus {
+    ready: bool,
+    error: Option<String>,
+}
+
+/// Liveness check
+///
+/// Always succeeds as long as the process is running
+#[utoipa::path(
+    get, path = "healthz",
+    responses(
+        (status = 200, description = "Success"),
+    ),
+    security(()),
+)]
+async fn healthz_get() -> ServerResult<()> {
+    new_ok_res(StatusCode::OK, ())
+}
+
+/// Readiness check
+///
+/// Checks whether the Auth service and the Data service are reachable and serving. Also fails once the gateway starts shutting down
+#[utoipa::path(
+    get, path = "readyz",
+    responses(
+        (status = 200, description = "All dependencies are ready", body = Readiness),
+        (status = 503, description = "At least one dependency is not ready", body = Readiness),
+        ExRes5XX,
+    ),
+    security(()),
+)]
+async fn readyz_get(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<ResultBody<Readiness>>) {
+
+    let timeout = Duration::from_millis(state.readiness_timeout);
+
+    let (auth, data) = tokio::join!(
+        check_dependency(state.auth_health_client.clone(), &state.auth_token, timeout),
+        check_dependency(state.data_health_client.clone(), &state.data_token, timeout),
+    );
+
+    let readiness = Readiness { auth, data };
+
+    // not accepting any new traffic once the shutdown has started
+
+    match readiness.auth.ready && readiness.data.ready && !state.shutdown.is_started() {
+        true => (
+            StatusCode::OK,
+            Json(ResultBody { success: true, error: None, data: Some(readiness) }),
+        ),
+        false => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ResultBody { success: false, error: Some("not ready".into()), data: Some(readiness) }),
+        ),
+    }
+}
+
+/// Calls the standard gRPC health check. Services that don't implement it are considered ready as long as they respond at all
+async fn check_dependency(mut client: HealthClient<Channel>, service_token: &str, timeout: Duration) -> DependencyStatus {
+    let check = call_grpc_service(
+        HealthCheckRequest { service: String::new() },
+        |req| client.check(req),
+        service_token,
+        "health.check",
+    );
+
+    let (ready, error) = match tokio::time::timeout(timeout, check).await {
+        Ok(Ok(res)) => match res.status() {
+            ServingStatus::Serving => (true, None),
+            status => (false, Some(format!("service status: {}", status.as_str_name()))),
+        },
+        Ok(Err(status)) if status.code() == tonic::Code::Unimplemented => (true, None),
+        Ok(Err(status)) => (false, Some(status.to_string())),
+        Err(_) => (false, Some(format!("timed out after {}ms", timeout.as_millis()))),
+    };
+
+    DependencyStatus { ready, error }
+}
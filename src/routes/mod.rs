@@ -1,45 +1,62 @@
-use axum::{extract::State, http::{header, Method}, middleware::{self, Next}, response::Response, Router};
+use axum::{extract::State, http::{header, Method}, middleware::{self, Next}, response::{IntoResponse, Response}, Router};
 use axum_extra::extract::CookieJar;
 use rand::{thread_rng, Rng};
 use tonic::transport::Channel;
+use tonic_health::pb::health_client::HealthClient;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing::{info, info_span};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use utoipa::{openapi::security::{ApiKey, ApiKeyValue, SecurityScheme}, OpenApi};
+use utoipa::{openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme}, OpenApi};
 use utoipa_swagger_ui::{Config, SwaggerUi};
 use utoipa_scalar::{Scalar, Servable as ScalarServable};
 
-use crate::{proto::{auth::auth_client::AuthClient, files::files_client::FilesClient, notes::notes_client::NotesClient, shelves::shelves_client::ShelvesClient, tags::tags_client::TagsClient}, types::{call_grpc_service, ResultBody}};
-use crate::{error::ResError, proto::auth::ValidateAtRequest, types::AppState};
+use crate::{proto::{auth::auth_client::AuthClient, files::files_client::FilesClient, notes::notes_client::NotesClient, shelves::shelves_client::ShelvesClient, tags::tags_client::TagsClient}, types::{call_grpc_service, get_token, ResultBody}};
+use files::tus;
+use crate::{connection::{new_lazy_channel, ConnectConfig}, csrf::csrf_middleware, idempotency::{IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED}, shutdown::in_flight_middleware, telemetry::{self, http_metrics_middleware}, error::ResError, fingerprint::Fingerprint, proto::auth::ValidateAtRequest, types::{AccessToken, AppState, CreateAndAddCookie, TokenSource}};
 
 mod auth;
 mod notes;
 mod tags;
-mod files;
+pub mod files;
 mod shelves;
+mod health;
+mod public;
+mod versions;
+mod ws;
 #[cfg(test)]
 mod tests;
 
-pub async fn get_rpc_clients(auth_url: String, data_url: String, max_chunk_size: usize) -> anyhow::Result<(
-    AuthClient<Channel>,
-    NotesClient<Channel>,
-    TagsClient<Channel>,
-    FilesClient<Channel>,
-    ShelvesClient<Channel>,
-)> {
-    Ok((
-        AuthClient::connect(auth_url).await?,
-        NotesClient::connect(data_url.clone()).await?,
-        TagsClient::connect(data_url.clone()).await?,
-        FilesClient::connect(data_url.clone()).await?
+/// All of the grpc clients that the gateway uses
+pub struct RpcClients {
+    pub auth: AuthClient<Channel>,
+    pub notes: NotesClient<Channel>,
+    pub tags: TagsClient<Channel>,
+    pub files: FilesClient<Channel>,
+    pub shelves: ShelvesClient<Channel>,
+    pub auth_health: HealthClient<Channel>,
+    pub data_health: HealthClient<Channel>,
+}
+
+/// Creates the grpc clients. The channels connect lazily, and all of the Data service clients share a single channel
+pub fn get_rpc_clients(auth_url: String, data_url: String, max_chunk_size: usize, config: ConnectConfig) -> anyhow::Result<RpcClients> {
+    let auth_channel = new_lazy_channel(auth_url, config)?;
+    let data_channel = new_lazy_channel(data_url, config)?;
+
+    Ok(RpcClients {
+        auth: AuthClient::new(auth_channel.clone()),
+        notes: NotesClient::new(data_channel.clone()),
+        tags: TagsClient::new(data_channel.clone()),
+        files: FilesClient::new(data_channel.clone())
             .max_decoding_message_size(1024 * 1024 * (max_chunk_size + 1)),
-        ShelvesClient::connect(data_url).await?,
-    ))
+        shelves: ShelvesClient::new(data_channel.clone()),
+        auth_health: HealthClient::new(auth_channel),
+        data_health: HealthClient::new(data_channel),
+    })
 }
 
 #[derive(OpenApi)]
 #[openapi(
-    info(description = "API documentation for [miku-notes-gateway](https://github.com/kutoru/miku-notes-gateway).<br>Note that despite the example response values, all response types are going to be wrapped inside of the `ResultBody` object as the `data` field.<br>Known documentation issues and their solutions:<br>- You might not be able to send cookies here. If that's the case, you'll have to send requests manually via some other application (like curl or Postman)<br>- Some nested type references are broken. All types are still available in the schema list, so you'll have to find them there"),
+    info(description = "API documentation for [miku-notes-gateway](https://github.com/kutoru/miku-notes-gateway).<br>Note that despite the example response values, all response types are going to be wrapped inside of the `ResultBody` object as the `data` field.<br>All `POST`, `PATCH` and `DELETE` requests that are authenticated with cookies must also copy the csrf cookie's value into the csrf header.<br>Known documentation issues and their solutions:<br>- You might not be able to send cookies here. If that's the case, you can either send requests manually via some other application (like curl or Postman), or get the tokens from `/login/token` and use the bearer auth instead<br>- Some nested type references are broken. All types are still available in the schema list, so you'll have to find them there"),
     modifiers(&SecurityAddon),
     components(schemas(ResultBody<()>)),
     nest(
@@ -48,6 +65,9 @@ pub async fn get_rpc_clients(auth_url: String, data_url: String, max_chunk_size:
         (path = "/tags", api = tags::Api),
         (path = "/files", api = files::Api),
         (path = "/shelf", api = shelves::Api),
+        (path = "/", api = health::Api),
+        (path = "/", api = public::Api),
+        (path = "/ws", api = ws::Api),
     ),
     tags(
         (name = "auth", description = "Auth management API"),
@@ -55,10 +75,29 @@ pub async fn get_rpc_clients(auth_url: String, data_url: String, max_chunk_size:
         (name = "tags", description = "Tag management API"),
         (name = "files", description = "File management API"),
         (name = "shelves", description = "Shelf management API"),
+        (name = "health", description = "Health check API"),
+        (name = "public", description = "Public file API"),
+        (name = "ws", description = "Change notification API"),
     ),
 )]
 struct ApiDoc;
 
+type GetRouter = fn(&AppState) -> Router;
+
+/// Routers behind `auth_middleware`, along with the paths that they get nested at
+const PROTECTED_ROUTERS: [(&str, GetRouter); 5] = [
+    ("/notes", notes::get_router),
+    ("/tags", tags::get_router),
+    ("/files", files::get_router),
+    ("/shelf", shelves::get_router),
+    ("/ws", ws::get_router),
+];
+
+/// Paths of the routes behind `auth_middleware`
+pub fn protected_paths() -> impl Iterator<Item = &'static str> {
+    PROTECTED_ROUTERS.into_iter().map(|(path, _)| path)
+}
+
 pub fn get_router(state: &AppState) -> anyhow::Result<Router> {
     let origins = [
         format!("http://127.0.0.1:{}", state.service_port).parse()?,
@@ -68,15 +107,25 @@ pub fn get_router(state: &AppState) -> anyhow::Result<Router> {
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::PATCH, Method::OPTIONS])
         .allow_origin(origins)
-        .allow_headers([header::ACCEPT, header::CONTENT_TYPE])
-        .expose_headers([header::CONTENT_DISPOSITION])
+        .allow_headers([
+            header::ACCEPT, header::CONTENT_TYPE, header::AUTHORIZATION, header::RANGE, header::IF_RANGE, header::IF_NONE_MATCH, header::IF_MATCH,
+            state.fingerprint_header.clone(), state.csrf_header.clone(), IDEMPOTENCY_KEY,
+            tus::TUS_RESUMABLE, tus::UPLOAD_LENGTH, tus::UPLOAD_OFFSET, tus::UPLOAD_METADATA,
+        ])
+        .expose_headers([
+            header::CONTENT_DISPOSITION, header::CONTENT_RANGE, header::ACCEPT_RANGES, header::ETAG, header::LOCATION, IDEMPOTENT_REPLAYED,
+            tus::TUS_RESUMABLE, tus::TUS_VERSION, tus::TUS_EXTENSION, tus::TUS_MAX_SIZE,
+            tus::UPLOAD_LENGTH, tus::UPLOAD_OFFSET, tus::UPLOAD_EXPIRES, tus::UPLOAD_FILE_ID,
+        ])
         .allow_credentials(true);
 
     let auth_router = auth::get_router(state);
-    let notes_router = notes::get_router(state);
-    let tags_router = tags::get_router(state);
-    let files_router = files::get_router(state);
-    let shelves_router = shelves::get_router(state);
+    let public_router = public::get_router(state);
+    let health_router = health::get_router(state);
+    let metrics_router = match state.metrics_port {
+        Some(_) => Router::new(),
+        None => telemetry::get_router(),
+    };
 
     setup_tracing(&state.log_level);
 
@@ -84,14 +133,18 @@ pub fn get_router(state: &AppState) -> anyhow::Result<Router> {
     std::env::set_var("access_token_key", &state.access_token_key);
     std::env::set_var("refresh_token_key", &state.refresh_token_key);
 
+    let protected_router = PROTECTED_ROUTERS
+        .into_iter()
+        .fold(Router::new(), |router, (path, get_router)| router.nest(path, get_router(state)));
+
     Ok(
-        Router::new()
-            .nest("/notes", notes_router)
-            .nest("/tags", tags_router)
-            .nest("/files", files_router)
-            .nest("/shelf", shelves_router)
+        protected_router
+            .route_layer(middleware::from_fn_with_state(state.clone(), csrf_middleware))
             .route_layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
             .merge(auth_router)
+            .merge(public_router)
+            .layer(middleware::from_fn(http_metrics_middleware))
+            .layer(middleware::from_fn_with_state(state.clone(), in_flight_middleware))
             .layer(cors)
             .layer(
                 TraceLayer::new_for_http()
@@ -107,6 +160,9 @@ pub fn get_router(state: &AppState) -> anyhow::Result<Router> {
                         info!(response_code = response.status().as_u16());
                     })
             )
+            // merged after the trace layer so that frequent probes don't flood the logs
+            .merge(health_router)
+            .merge(metrics_router)
             .merge(
                 SwaggerUi::new("/swagger-ui")
                     .url("/swagger-ui/openapi.json", ApiDoc::openapi())
@@ -119,22 +175,66 @@ pub fn get_router(state: &AppState) -> anyhow::Result<Router> {
 async fn auth_middleware(
     jar: CookieJar,
     State(mut state): State<AppState>,
+    fingerprint: Result<Fingerprint, ResError>,
     mut req: axum::extract::Request,
     next: Next,
 ) -> Result<Response, ResError> {
-    let token = match jar.get(&state.access_token_key) {
-        Some(c) => c.value(),
-        None => return Err(ResError::Unauthorized("Could not get access token from the cookie jar".into())),
+    let token = get_token(&jar, req.headers(), &state.access_token_key, state.token_precedence)
+        .map(|(token, source)| (token.to_string(), source));
+
+    let validation = match token {
+        Some((token, source)) => {
+            req.extensions_mut().insert(source);
+            req.extensions_mut().insert(AccessToken(token.clone()));
+            validate_access_token(&mut state, token).await
+        },
+        None => Err(ResError::Unauthorized("Could not get access token from either the cookie jar or the authorization header".into())),
     };
 
+    let refresh_token = jar.get(&state.refresh_token_key).map(|c| c.value());
+
+    match (validation, refresh_token) {
+        (Ok(user_id), _) => {
+            req.extensions_mut().insert(user_id);
+            Ok(next.run(req).await)
+        },
+
+        // if the access token is missing or expired, trying to get a new one with the refresh cookie,
+        // and then sending it back along with the actual response
+
+        (Err(ResError::Unauthorized(_)), Some(refresh_token)) if state.auto_refresh => {
+            let Fingerprint(fingerprint) = fingerprint?;
+            let access_token = auth::get_new_access_token(&state, refresh_token, fingerprint).await?;
+            let user_id = validate_access_token(&mut state, access_token.clone()).await?;
+
+            req.extensions_mut().insert(user_id);
+            req.extensions_mut().insert(TokenSource::Cookie);
+            req.extensions_mut().insert(AccessToken(access_token.clone()));
+            let cookies = &state.cookie_config;
+            let jar = CookieJar::new().add_new_cookie(state.access_token_key, access_token, state.access_token_ttl, &cookies.path, cookies);
+
+            Ok((jar, next.run(req).await).into_response())
+        },
+
+        (Err(e), _) => Err(e),
+    }
+}
+
+/// Gets the user id that the access token belongs to, either from the token cache or from the Auth service
+async fn validate_access_token(state: &mut AppState, access_token: String) -> Result<i32, ResError> {
+    if let Some(user_id) = state.token_cache.get(&access_token) {
+        return Ok(user_id);
+    }
+
     let res_body = call_grpc_service(
-        ValidateAtRequest { access_token: token.into() },
+        ValidateAtRequest { access_token: access_token.clone() },
         |req| state.auth_client.validate_access_token(req),
         &state.auth_token,
+        "auth.validate_access_token",
     ).await?;
 
-    req.extensions_mut().insert(res_body.user_id);
-    Ok(next.run(req).await)
+    state.token_cache.insert(&access_token, res_body.user_id);
+    Ok(res_body.user_id)
 }
 
 fn setup_tracing(log_level: &tracing::Level) {
@@ -185,6 +285,11 @@ impl utoipa::Modify for SecurityAddon {
             SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(rt_key))),
         );
 
+        components.add_security_scheme(
+            "bearer_token",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+
         openapi.components = Some(components);
     }
 }
@@ -1,9 +1,18 @@
 use crate::proto::files::File;
 use crate::proto::notes::{AttachTagReq, CreateNoteReq, DeleteNoteReq, DetachTagReq, Empty, Note, NoteList, UpdateNoteReq};
 use crate::proto::tags::Tag;
+use crate::error::ResError;
+use crate::idempotency::idempotency_middleware;
+use crate::events::ChangeEvent;
+use crate::routes::versions::{if_match, if_match_last_edited, new_versioned_res, precondition_failed};
+use crate::routes::files::{archive::zip_response, listing::{find_note, find_note_edited_at}};
 use crate::types::{call_grpc_service, new_ok_res, AppState, ExRes400, ExRes401, ExRes404, ExRes415, ExRes422, ExRes5XX, Json, ServerResult};
 
 use axum::extract::Query;
+use axum::handler::Handler;
+use axum::http::HeaderMap;
+use axum::middleware;
+use axum::response::Response;
 use axum::routing::{delete, post};
 use axum::{Router, routing::{patch, get}, extract::{State, Path}, http::StatusCode, Extension};
 use utoipa::OpenApi;
@@ -13,16 +22,17 @@ mod helpers;
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(notes_get, notes_post, notes_patch, notes_delete, notes_tag_post, notes_tag_delete),
+    paths(notes_get, notes_post, notes_patch, notes_delete, notes_tag_post, notes_tag_delete, notes_files_zip_get),
     components(schemas(File, Tag, Note, NoteList, CreateNoteReq, UpdateNoteReq, Empty, AttachTagReq)),
-    security(("access_token" = [])),
+    security(("access_token" = []), ("bearer_token" = [])),
 )]
 pub struct Api;
 
 pub fn get_router(state: &AppState) -> Router {
     Router::new()
-        .route("/", get(notes_get).post(notes_post))
+        .route("/", get(notes_get).post(notes_post.layer(middleware::from_fn_with_state(state.clone(), idempotency_middleware))))
         .route("/:id", patch(notes_patch).delete(notes_delete))
+        .route("/:id/files.zip", get(notes_files_zip_get))
         .route("/:id/tag", post(notes_tag_post))
         .route("/:id/tag/:id", delete(notes_tag_delete))
         .with_state(state.clone())
@@ -59,6 +69,7 @@ async fn notes_get(
         body,
         |req| state.notes_client.read_notes(req),
         &state.data_token,
+        "notes.read_notes",
     ).await?;
 
     new_ok_res(StatusCode::OK, note_list)
@@ -69,9 +80,13 @@ async fn notes_get(
 /// Note that you cannot attach files or tags during note creation. First, you have to create a note, and then call the respective attach routes
 #[utoipa::path(
     post, path = "",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes the request safe to retry. Repeats with the same key get the first response back"),
+    ),
     request_body(content = CreateNoteReq),
     responses(
-        (status = 201, description = "Success", body = Note),
+        (status = 201, description = "Success. The note's version is in the `ETag` header", body = Note),
+        (status = 409, description = "The idempotency key has been used for a different request, or the first request with it is still in progress"),
         ExRes400, ExRes401, ExRes415, ExRes422, ExRes5XX,
     ),
 )]
@@ -80,7 +95,7 @@ async fn notes_post(
     State(mut state): State<AppState>,
     Extension(user_id): Extension<i32>,
     Json(mut body): Json<CreateNoteReq>,
-) -> ServerResult<Note> {
+) -> Result<Response, ResError> {
 
     body.user_id = user_id;
 
@@ -88,27 +103,61 @@ async fn notes_post(
         body,
         |req| state.notes_client.create_note(req),
         &state.data_token,
+        "notes.create_note",
     ).await?;
 
-    new_ok_res(StatusCode::CREATED, new_note)
+    state.events.publish(user_id, ChangeEvent::NoteCreated(new_note.clone()));
+
+    new_versioned_res(StatusCode::CREATED, new_note)
 }
 
 /// Update a note
+///
+/// With `If-Match`, the note only gets updated if it hasn't changed since the given version.
+/// The version of a note is `"{times_edited}-{last_edited}"`, which is also sent in the `ETag` header
 #[utoipa::path(
     patch, path = "/{note_id}",
+    params(
+        ("If-Match" = Option<String>, Header, description = "Version of the note that the changes were made to"),
+    ),
     request_body(content = UpdateNoteReq),
     responses(
-        (status = 200, description = "Success", body = Note),
+        (status = 200, description = "Success. The note's new version is in the `ETag` header", body = Note),
+        (status = 412, description = "The note has changed since the version in `If-Match`. The current note is in the `data` field", body = Note),
         ExRes400, ExRes401, ExRes404, ExRes415, ExRes422, ExRes5XX,
     ),
 )]
-#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+#[tracing::instrument(skip(state, headers), err(level = tracing::Level::DEBUG))]
 async fn notes_patch(
     State(mut state): State<AppState>,
     Path(note_id): Path<i32>,
     Extension(user_id): Extension<i32>,
+    headers: HeaderMap,
     Json(mut body): Json<UpdateNoteReq>,
-) -> ServerResult<Note> {
+) -> Result<Response, ResError> {
+
+    // the Data service can't update conditionally, so there is still a small window between the check and the update
+
+    if let Some(if_match) = if_match(&headers) {
+
+        // usually the client has the current version, which can be found with a single request.
+        // otherwise the note has to be found among all of them, so that the current version can be sent back
+
+        let current = match if_match_last_edited(if_match) {
+            Some(last_edited) => find_note_edited_at(&state, user_id, note_id, last_edited).await?,
+            None => None,
+        };
+
+        let current = match current {
+            Some(note) => note,
+            None => find_note(&state, user_id, note_id).await?
+                .ok_or(ResError::NotFound(format!("Could not find note {note_id}")))?,
+        };
+
+        if let Some(res) = precondition_failed(if_match, current) {
+            return Ok(res);
+        }
+    }
 
     body.id = note_id;
     body.user_id = user_id;
@@ -117,9 +166,12 @@ async fn notes_patch(
         body,
         |req| state.notes_client.update_note(req),
         &state.data_token,
+        "notes.update_note",
     ).await?;
 
-    new_ok_res(StatusCode::OK, updated_note)
+    state.events.publish(user_id, ChangeEvent::NoteUpdated(updated_note.clone()));
+
+    new_versioned_res(StatusCode::OK, updated_note)
 }
 
 /// Delete a note
@@ -141,8 +193,12 @@ async fn notes_delete(
         DeleteNoteReq { id: note_id, user_id },
         |req| state.notes_client.delete_note(req),
         &state.data_token,
+        "notes.delete_note",
     ).await?;
 
+    state.quota_tracker.invalidate(user_id);
+    state.events.publish(user_id, ChangeEvent::NoteDeleted { id: note_id });
+
     new_ok_res(StatusCode::OK, res_body)
 }
 
@@ -165,13 +221,17 @@ async fn notes_tag_post(
 
     body.note_id = note_id;
     body.user_id = user_id;
+    let tag_id = body.tag_id;
 
     let res_body = call_grpc_service(
         body,
         |req| state.notes_client.attach_tag(req),
         &state.data_token,
+        "notes.attach_tag",
     ).await?;
 
+    state.events.publish(user_id, ChangeEvent::NoteTagAttached { note_id, tag_id });
+
     new_ok_res(StatusCode::OK, res_body)
 }
 
@@ -194,7 +254,35 @@ async fn notes_tag_delete(
         DetachTagReq { user_id, note_id, tag_id },
         |req| state.notes_client.detach_tag(req),
         &state.data_token,
+        "notes.detach_tag",
     ).await?;
 
+    state.events.publish(user_id, ChangeEvent::NoteTagDetached { note_id, tag_id });
+
     new_ok_res(StatusCode::OK, res_body)
 }
+
+/// Download all files of a note
+///
+/// Streams all of the note's files in a single zip archive. The archive also contains `manifest.json`,
+/// which describes each file, along with the files that could not be downloaded.
+/// Files with the same name get numbered, like `name (1).txt`
+#[utoipa::path(
+    get, path = "/{note_id}/files.zip",
+    responses(
+        (status = 200, description = "Archive is being sent", body = Vec<u8>, content_type = "application/zip"),
+        ExRes400, ExRes401, ExRes404, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+async fn notes_files_zip_get(
+    State(state): State<AppState>,
+    Path(note_id): Path<i32>,
+    Extension(user_id): Extension<i32>,
+) -> Result<Response, ResError> {
+
+    let note = find_note(&state, user_id, note_id).await?
+        .ok_or(ResError::NotFound(format!("Could not find note {note_id}")))?;
+
+    Ok(zip_response(&state, user_id, &format!("note-{note_id}.zip"), note.files))
+}
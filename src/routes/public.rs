@@ -0,0 +1,60 @@
+use axum::{extract::{Path, Query, State}, http::HeaderMap, response::Response, routing::get, Router};
+use utoipa::OpenApi;
+
+use crate::{error::ResError, types::{AppState, ExRes400, ExRes404, ExRes5XX}};
+
+use super::files::{disposition::DownloadQuery, download_file};
+
+pub fn get_router(state: &AppState) -> Router {
+    Router::new()
+        .route("/public/dl/:token", get(public_dl_get))
+        .with_state(state.clone())
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(public_dl_get))]
+pub struct Api;
+
+/// Download a file through a public link
+///
+/// Works the same way as `/files/dl/{file_hash}`, except that the access is granted by the link's token instead of the auth cookies.
+/// Each successful response counts as a download, including the ones for a range of the file,
+/// so links with a download limit aren't meant for seeking in media files
+#[utoipa::path(
+    get, path = "public/dl/{token}",
+    params(
+        ("disposition" = Option<String>, Query, description = "How the browser should handle the file. v can be one of: `attachment`, `inline`. Defaults to `attachment`."),
+    ),
+    responses(
+        (status = 200, description = "File has been successfully sent", body = Vec<u8>, content_type = "*/*"),
+        (status = 206, description = "Requested range of the file has been successfully sent", body = Vec<u8>, content_type = "*/*"),
+        (status = 403, description = "The link is invalid, has expired, or has reached its download limit"),
+        ExRes400, ExRes404, ExRes5XX,
+    ),
+    security(()),
+)]
+#[tracing::instrument(skip(state, token, headers), err(level = tracing::Level::DEBUG))]
+async fn public_dl_get(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Query(query): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ResError> {
+
+    let inline = query.inline()?;
+    let claims = state.link_signer.verify(&token)?;
+    state.link_signer.take_download(&claims)?;
+
+    let response = download_file(&state, claims.user_id, claims.file_hash.clone(), &headers, inline).await;
+
+    // every successful response counts, including the partial ones. counting only the ones that start from the beginning
+    // would let the rest of the file be downloaded any number of times with ranges that start from the second byte
+
+    let counts = response.as_ref().is_ok_and(|res| res.status().is_success());
+
+    if !counts {
+        state.link_signer.give_back_download(&claims);
+    }
+
+    response
+}
@@ -1,20 +1,26 @@
-use axum::{extract::State, http::StatusCode, routing::{get, post}, Extension, Router};
+use axum::{extract::State, handler::Handler, http::{HeaderMap, StatusCode}, middleware, response::Response, routing::{get, post}, Extension, Router};
 use utoipa::OpenApi;
 
-use crate::{proto::shelves::{ClearShelfReq, ConvertToNoteReq, ReadShelfReq, Shelf, UpdateShelfReq}, types::{call_grpc_service, new_ok_res, AppState, ExRes400, ExRes401, ExRes404, ExRes415, ExRes422, ExRes5XX, Json, ServerResult}};
+use crate::error::ResError;
+use crate::events::ChangeEvent;
+use crate::idempotency::idempotency_middleware;
+use crate::routes::versions::{if_match, new_versioned_res, precondition_failed};
+use crate::routes::files::{archive::zip_response, listing::read_shelf};
+use crate::{proto::shelves::{ClearShelfReq, ConvertToNoteReq, ReadShelfReq, Shelf, UpdateShelfReq}, types::{call_grpc_service, AppState, ExRes400, ExRes401, ExRes404, ExRes415, ExRes422, ExRes5XX, Json}};
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(shelf_get, shelf_patch, shelf_delete, shelf_to_note_post),
+    paths(shelf_get, shelf_patch, shelf_delete, shelf_to_note_post, shelf_files_zip_get),
     components(schemas(Shelf, UpdateShelfReq, ConvertToNoteReq)),
-    security(("access_token" = [])),
+    security(("access_token" = []), ("bearer_token" = [])),
 )]
 pub struct Api;
 
 pub fn get_router(state: &AppState) -> Router {
     Router::new()
         .route("/", get(shelf_get).patch(shelf_patch).delete(shelf_delete))
-        .route("/to-note", post(shelf_to_note_post))
+        .route("/to-note", post(shelf_to_note_post.layer(middleware::from_fn_with_state(state.clone(), idempotency_middleware))))
+        .route("/files.zip", get(shelf_files_zip_get))
         .with_state(state.clone())
 }
 
@@ -22,7 +28,7 @@ pub fn get_router(state: &AppState) -> Router {
 #[utoipa::path(
     get, path = "",
     responses(
-        (status = 200, description = "Success", body = Shelf),
+        (status = 200, description = "Success. The shelf's version is in the `ETag` header", body = Shelf),
         ExRes401, ExRes5XX,
     ),
 )]
@@ -30,34 +36,47 @@ pub fn get_router(state: &AppState) -> Router {
 async fn shelf_get(
     State(mut state): State<AppState>,
     Extension(user_id): Extension<i32>,
-) -> ServerResult<Shelf> {
+) -> Result<Response, ResError> {
 
     let shelf = call_grpc_service(
         ReadShelfReq { user_id },
         |req| state.shelves_client.read_shelf(req),
         &state.data_token,
+        "shelves.read_shelf",
     ).await?;
 
-    new_ok_res(StatusCode::OK, shelf)
+    new_versioned_res(StatusCode::OK, shelf)
 }
 
 /// Update the shelf
 ///
-/// Note that in order to update individual shelf's files, you'll have to call the file routes
+/// Note that in order to update individual shelf's files, you'll have to call the file routes.
+/// With `If-Match`, the shelf only gets updated if it hasn't changed since the given version, the same way as with notes
 #[utoipa::path(
     patch, path = "",
+    params(
+        ("If-Match" = Option<String>, Header, description = "Version of the shelf that the changes were made to"),
+    ),
     request_body(content = UpdateShelfReq),
     responses(
-        (status = 200, description = "Success", body = Shelf),
+        (status = 200, description = "Success. The shelf's new version is in the `ETag` header", body = Shelf),
+        (status = 412, description = "The shelf has changed since the version in `If-Match`. The current shelf is in the `data` field", body = Shelf),
         ExRes400, ExRes401, ExRes404, ExRes415, ExRes422, ExRes5XX,
     ),
 )]
-#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+#[tracing::instrument(skip(state, headers), err(level = tracing::Level::DEBUG))]
 async fn shelf_patch(
     State(mut state): State<AppState>,
     Extension(user_id): Extension<i32>,
+    headers: HeaderMap,
     Json(mut body): Json<UpdateShelfReq>,
-) -> ServerResult<Shelf> {
+) -> Result<Response, ResError> {
+
+    if let Some(if_match) = if_match(&headers) {
+        if let Some(res) = precondition_failed(if_match, read_shelf(&state, user_id).await?) {
+            return Ok(res);
+        }
+    }
 
     body.user_id = user_id;
 
@@ -65,9 +84,12 @@ async fn shelf_patch(
         body,
         |req| state.shelves_client.update_shelf(req),
         &state.data_token,
+        "shelves.update_shelf",
     ).await?;
 
-    new_ok_res(StatusCode::OK, shelf)
+    state.events.publish(user_id, ChangeEvent::ShelfUpdated(shelf.clone()));
+
+    new_versioned_res(StatusCode::OK, shelf)
 }
 
 /// Clear the shelf
@@ -76,7 +98,7 @@ async fn shelf_patch(
 #[utoipa::path(
     delete, path = "",
     responses(
-        (status = 200, description = "Success", body = Shelf),
+        (status = 200, description = "Success. The shelf's new version is in the `ETag` header", body = Shelf),
         ExRes400, ExRes401, ExRes404, ExRes5XX,
     ),
 )]
@@ -84,15 +106,19 @@ async fn shelf_patch(
 async fn shelf_delete(
     State(mut state): State<AppState>,
     Extension(user_id): Extension<i32>,
-) -> ServerResult<Shelf> {
+) -> Result<Response, ResError> {
 
     let shelf = call_grpc_service(
         ClearShelfReq { user_id },
         |req| state.shelves_client.clear_shelf(req),
         &state.data_token,
+        "shelves.clear_shelf",
     ).await?;
 
-    new_ok_res(StatusCode::OK, shelf)
+    state.quota_tracker.invalidate(user_id);
+    state.events.publish(user_id, ChangeEvent::ShelfUpdated(shelf.clone()));
+
+    new_versioned_res(StatusCode::OK, shelf)
 }
 
 /// Convert the shelf to a note
@@ -100,9 +126,13 @@ async fn shelf_delete(
 /// Clears the shelf while creating a new note. Any attached files will automatically transfer to the newly created note
 #[utoipa::path(
     post, path = "/to-note",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes the request safe to retry. Repeats with the same key get the first response back"),
+    ),
     request_body(content = ConvertToNoteReq),
     responses(
-        (status = 201, description = "Success", body = Shelf),
+        (status = 201, description = "Success. The shelf's new version is in the `ETag` header", body = Shelf),
+        (status = 409, description = "The idempotency key has been used for a different request, or the first request with it is still in progress"),
         ExRes400, ExRes401, ExRes415, ExRes422, ExRes5XX,
     ),
 )]
@@ -111,7 +141,7 @@ async fn shelf_to_note_post(
     State(mut state): State<AppState>,
     Extension(user_id): Extension<i32>,
     Json(mut body): Json<ConvertToNoteReq>,
-) -> ServerResult<Shelf> {
+) -> Result<Response, ResError> {
 
     body.user_id = user_id;
 
@@ -119,7 +149,34 @@ async fn shelf_to_note_post(
         body,
         |req| state.shelves_client.convert_to_note(req),
         &state.data_token,
+        "shelves.convert_to_note",
     ).await?;
 
-    new_ok_res(StatusCode::OK, shelf)
+    // the new note doesn't come back, so the clients have to fetch it themselves
+
+    state.events.publish(user_id, ChangeEvent::ShelfUpdated(shelf.clone()));
+    state.events.publish(user_id, ChangeEvent::NotesChanged);
+
+    new_versioned_res(StatusCode::OK, shelf)
+}
+
+/// Download all files of the shelf
+///
+/// Streams all of the shelf's files in a single zip archive, the same way as `/notes/{note_id}/files.zip` does
+#[utoipa::path(
+    get, path = "/files.zip",
+    responses(
+        (status = 200, description = "Archive is being sent", body = Vec<u8>, content_type = "application/zip"),
+        ExRes401, ExRes5XX,
+    ),
+)]
+#[tracing::instrument(skip(state), err(level = tracing::Level::DEBUG))]
+async fn shelf_files_zip_get(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<i32>,
+) -> Result<Response, ResError> {
+
+    let shelf = read_shelf(&state, user_id).await?;
+
+    Ok(zip_response(&state, user_id, "shelf.zip", shelf.files))
 }
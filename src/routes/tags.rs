@@ -1,19 +1,22 @@
-use axum::{extract::{Path, State}, http::StatusCode, routing::{get, patch}, Extension, Router};
+use axum::{extract::{Path, State}, handler::Handler, http::StatusCode, middleware, routing::{get, patch}, Extension, Router};
 use utoipa::OpenApi;
 
+use crate::events::ChangeEvent;
+use crate::idempotency::idempotency_middleware;
+
 use crate::{proto::tags::{CreateTagReq, DeleteTagReq, Empty, ReadTagsReq, Tag, TagList, UpdateTagReq}, types::{call_grpc_service, new_ok_res, AppState, ExRes400, ExRes5XX, ExRes415, ExRes404, ExRes401, ExRes422, Json, ServerResult}};
 
 #[derive(OpenApi)]
 #[openapi(
     paths(tags_get, tags_post, tags_patch, tags_delete),
     components(schemas(Tag, TagList, CreateTagReq, UpdateTagReq, Empty)),
-    security(("access_token" = [])),
+    security(("access_token" = []), ("bearer_token" = [])),
 )]
 pub struct Api;
 
 pub fn get_router(state: &AppState) -> Router {
     Router::new()
-        .route("/", get(tags_get).post(tags_post))
+        .route("/", get(tags_get).post(tags_post.layer(middleware::from_fn_with_state(state.clone(), idempotency_middleware))))
         .route("/:id", patch(tags_patch).delete(tags_delete))
         .with_state(state.clone())
 }
@@ -36,6 +39,7 @@ async fn tags_get(
         ReadTagsReq { user_id },
         |req| state.tags_client.read_tags(req),
         &state.data_token,
+        "tags.read_tags",
     ).await?;
 
     new_ok_res(StatusCode::OK, tag_list)
@@ -44,9 +48,13 @@ async fn tags_get(
 /// Create a tag
 #[utoipa::path(
     post, path = "",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes the request safe to retry. Repeats with the same key get the first response back"),
+    ),
     request_body(content = CreateTagReq),
     responses(
         (status = 201, description = "Success", body = Tag),
+        (status = 409, description = "The idempotency key has been used for a different request, or the first request with it is still in progress"),
         ExRes400, ExRes401, ExRes415, ExRes422, ExRes5XX,
     ),
 )]
@@ -63,8 +71,11 @@ async fn tags_post(
         body,
         |req| state.tags_client.create_tag(req),
         &state.data_token,
+        "tags.create_tag",
     ).await?;
 
+    state.events.publish(user_id, ChangeEvent::TagCreated(new_tag.clone()));
+
     new_ok_res(StatusCode::CREATED, new_tag)
 }
 
@@ -92,8 +103,11 @@ async fn tags_patch(
         body,
         |req| state.tags_client.update_tag(req),
         &state.data_token,
+        "tags.update_tag",
     ).await?;
 
+    state.events.publish(user_id, ChangeEvent::TagUpdated(updated_tag.clone()));
+
     new_ok_res(StatusCode::OK, updated_tag)
 }
 
@@ -116,7 +130,10 @@ async fn tags_delete(
         DeleteTagReq { id: tag_id, user_id },
         |req| state.tags_client.delete_tag(req),
         &state.data_token,
+        "tags.delete_tag",
     ).await?;
 
+    state.events.publish(user_id, ChangeEvent::TagDeleted { id: tag_id });
+
     new_ok_res(StatusCode::OK, res_body)
 }
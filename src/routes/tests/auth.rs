@@ -0,0 +1,244 @@
+use axum::{body::Body, http::{Request, StatusCode}, response::Response, Router};
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+use crate::types::cookie_path_matches;
+
+use super::{authorize, authorize_with_fingerprint, fingerprint_header, get_app, get_app_with, login, login_set_cookies, new_body};
+
+async fn access_get(app: Router, refresh_token: &str, fingerprint: &str) -> StatusCode {
+    let request = Request::builder()
+        .uri("/access")
+        .header("cookie", refresh_token)
+        .header(fingerprint_header(), fingerprint)
+        .body(Body::empty())
+        .unwrap();
+
+    app
+        .oneshot(request)
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn access_get_same_fingerprint() {
+    let mut app = get_app().await;
+    let (_, rt) = authorize_with_fingerprint(&mut app, "first device").await;
+
+    assert_eq!(StatusCode::OK, access_get(app, &rt, "first device").await);
+}
+
+#[tokio::test]
+async fn access_get_fingerprint_mismatch() {
+    let mut app = get_app().await;
+    let (_, rt) = authorize_with_fingerprint(&mut app, "first device").await;
+
+    assert_eq!(StatusCode::UNAUTHORIZED, access_get(app, &rt, "second device").await);
+}
+
+#[tokio::test]
+async fn tags_get_with_bearer_token() {
+    let app = get_app().await;
+
+    let request = Request::builder()
+        .method("POST")
+        .uri("/login/token")
+        .header("content-type", "application/json")
+        .body(new_body(
+            json!({ "email": "nexochan@mail.ru", "password": "1234" }),
+        ))
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::OK, response.status());
+    assert!(response.headers().get("set-cookie").is_none());
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    let access_token = body["data"]["access_token"].as_str().expect("Did not get the access token in the body");
+
+    let request = Request::builder()
+        .uri("/tags")
+        .header("authorization", format!("Bearer {access_token}"))
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+async fn tags_get_with_refresh_token_only(auto_refresh: bool) -> Response {
+    let mut app = get_app_with(|state| state.auto_refresh = auto_refresh).await;
+    let (_, rt) = authorize(&mut app).await;
+
+    let request = Request::builder()
+        .uri("/tags")
+        .header("cookie", rt)
+        .header(fingerprint_header(), "")
+        .body(Body::empty())
+        .unwrap();
+
+    app.oneshot(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn tags_get_auto_refresh() {
+    let response = tags_get_with_refresh_token_only(true).await;
+
+    assert_eq!(StatusCode::OK, response.status());
+
+    let new_cookie = response.headers().get("set-cookie").expect("Did not get the new access cookie");
+    assert!(new_cookie.to_str().unwrap().starts_with("at="));
+}
+
+#[tokio::test]
+async fn tags_get_auto_refresh_disabled() {
+    let response = tags_get_with_refresh_token_only(false).await;
+
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+}
+
+/// Logs in with the refresh cookie on the `refresh_path`, and then requests `/tags` with the cookies that a browser would send there,
+/// except for the access cookie, as if it has expired
+async fn tags_get_auto_refresh_with_refresh_path(refresh_path: &str) -> Response {
+    let mut app = get_app_with(|state| {
+        state.auto_refresh = true;
+        state.cookie_config.refresh_path = refresh_path.into();
+    }).await;
+
+    let cookies: Vec<_> = login_set_cookies(&mut app, "")
+        .await
+        .into_iter()
+        .filter(|c| !c.starts_with("at="))
+        .filter(|c| {
+            let path = c.split(';').find_map(|a| a.trim().strip_prefix("Path=")).unwrap_or("/");
+            cookie_path_matches(path, "/tags")
+        })
+        .map(|c| c.split(';').next().unwrap().to_string())
+        .collect();
+
+    let request = Request::builder()
+        .uri("/tags")
+        .header("cookie", cookies.join("; "))
+        .header(fingerprint_header(), "")
+        .body(Body::empty())
+        .unwrap();
+
+    app.oneshot(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn tags_get_auto_refresh_root_refresh_path() {
+    let response = tags_get_auto_refresh_with_refresh_path("/").await;
+
+    assert_eq!(StatusCode::OK, response.status());
+    assert!(response.headers().get("set-cookie").is_some_and(|c| c.to_str().unwrap().starts_with("at=")));
+}
+
+#[tokio::test]
+async fn tags_get_auto_refresh_scoped_refresh_path() {
+
+    // the refresh cookie never reaches /tags, so there is nothing to refresh the token with
+
+    let response = tags_get_auto_refresh_with_refresh_path("/access").await;
+
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+}
+
+#[tokio::test]
+async fn tags_get_after_logout() {
+    let mut app = get_app().await;
+    let (at, _) = authorize(&mut app).await;
+
+    let tags_request = || Request::builder()
+        .uri("/tags")
+        .header("cookie", &at)
+        .body(Body::empty())
+        .unwrap();
+
+    // the first request puts the token in the cache
+
+    let response = app.clone().oneshot(tags_request()).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+
+    let request = Request::builder()
+        .uri("/logout")
+        .header("cookie", &at)
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+
+    let response = app.oneshot(tags_request()).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+}
+
+/// `csrf_header` receives the token from the csrf cookie and returns the value for the csrf header
+async fn tags_post_with_csrf(csrf_header: impl FnOnce(&str) -> Option<String>) -> StatusCode {
+    let mut app = get_app().await;
+    let cookies = login(&mut app, "").await;
+    let csrf_cookie = cookies.get("csrf").expect("Did not get the csrf cookie");
+
+    let mut request = Request::builder()
+        .method("POST")
+        .uri("/tags")
+        .header("content-type", "application/json")
+        .header("cookie", format!("{}; {}", cookies["at"], csrf_cookie));
+
+    if let Some(value) = csrf_header(csrf_cookie.split_once('=').unwrap().1) {
+        request = request.header(dotenvy::var("CSRF_HEADER").unwrap(), value);
+    }
+
+    let request = request
+        .body(new_body(json!({ "name": "csrf test tag" })))
+        .unwrap();
+
+    app.oneshot(request).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn tags_post_csrf_missing() {
+    assert_eq!(StatusCode::FORBIDDEN, tags_post_with_csrf(|_| None).await);
+}
+
+#[tokio::test]
+async fn tags_post_csrf_mismatch() {
+    assert_eq!(StatusCode::FORBIDDEN, tags_post_with_csrf(|_| Some("definitely not the token".into())).await);
+}
+
+#[tokio::test]
+async fn tags_post_csrf_ok() {
+    assert_eq!(StatusCode::CREATED, tags_post_with_csrf(|token| Some(token.into())).await);
+}
+
+#[tokio::test]
+async fn login_post_new_csrf_token() {
+    let app = get_app().await;
+    let planted = format!("csrf={}", "a".repeat(32));
+
+    let request = Request::builder()
+        .method("POST")
+        .uri("/login")
+        .header("content-type", "application/json")
+        .header("cookie", &planted)
+        .header(fingerprint_header(), "")
+        .body(new_body(json!({ "email": "nexochan@mail.ru", "password": "1234" })))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+
+    let csrf_cookie = response.headers()
+        .get_all("set-cookie")
+        .into_iter()
+        .filter_map(|v| v.to_str().ok()?.split(';').next())
+        .find(|c| c.starts_with("csrf="))
+        .expect("Did not get the csrf cookie");
+
+    assert_ne!(planted, csrf_cookie);
+}
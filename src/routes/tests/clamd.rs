@@ -0,0 +1,51 @@
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+/// The standard antivirus test string, which clamd detects as `Eicar-Signature`
+pub const EICAR: &str = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+/// Starts a fake clamd that speaks the INSTREAM protocol and detects `EICAR` in the scanned data. Returns its address
+pub async fn start_fake_clamd() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle_scan(stream));
+        }
+    });
+
+    addr
+}
+
+async fn handle_scan(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut command = [0; 10];
+    stream.read_exact(&mut command).await?;
+    assert_eq!(b"zINSTREAM\0", &command);
+
+    let mut data = Vec::new();
+
+    loop {
+        let len = stream.read_u32().await? as usize;
+        if len == 0 {
+            break;
+        }
+
+        let start = data.len();
+        data.resize(start + len, 0);
+        stream.read_exact(&mut data[start..]).await?;
+    }
+
+    let reply = match data.windows(EICAR.len()).any(|w| w == EICAR.as_bytes()) {
+        true => "stream: Eicar-Signature FOUND\0",
+        false => "stream: OK\0",
+    };
+
+    stream.write_all(reply.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Address that nothing is listening on
+pub async fn unused_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().to_string()
+}
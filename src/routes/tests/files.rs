@@ -0,0 +1,564 @@
+use std::sync::Arc;
+
+use axum::{body::Body, http::{request::Builder, Request, StatusCode}, response::Response, Router};
+use http_body_util::BodyExt;
+use serde_json::Value;
+use tower::ServiceExt;
+
+use crate::scanner::{ScanPolicy, Scanner};
+
+use super::clamd::{start_fake_clamd, unused_addr, EICAR};
+use super::{authorized_request, get_app, get_app_with};
+
+async fn tus_request(app: &mut Router, method: &str, uri: &str) -> Builder {
+    authorized_request(app, method, uri).await
+        .header("tus-resumable", "1.0.0")
+}
+
+fn header<'a>(response: &'a Response, name: &str) -> &'a str {
+    response.headers().get(name).unwrap_or_else(|| panic!("Did not get the {name} header")).to_str().unwrap()
+}
+
+#[tokio::test]
+async fn files_post_without_files() {
+    let mut app = get_app().await;
+
+    let body = "--boundary\r\n\
+        Content-Disposition: form-data; name=\"note_id\"\r\n\r\n\
+        1\r\n\
+        --boundary--\r\n";
+
+    let request = authorized_request(&mut app, "POST", "/files").await
+        .header("content-type", "multipart/form-data; boundary=boundary")
+        .body(Body::from(body))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+
+#[tokio::test]
+async fn files_usage_get() {
+    let mut app = get_app_with(|state| state.user_file_quota = 1000).await;
+
+    let request = authorized_request(&mut app, "GET", "/files/usage").await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(Some(1000), body["data"]["max_files"].as_u64());
+    assert!(body["data"]["max_bytes"].is_null());
+}
+
+#[tokio::test]
+async fn tus_post_over_quota() {
+    let mut app = get_app_with(|state| state.user_storage_quota = 1).await;
+
+    let request = tus_request(&mut app, "POST", "/files/tus").await
+        .header("upload-length", (2 * 1024 * 1024).to_string())
+        .header("upload-metadata", "filename dGVzdC50eHQ=,note_id MQ==")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::INSUFFICIENT_STORAGE, response.status());
+}
+
+#[tokio::test]
+async fn tus_post_staged_uploads_count_toward_quota() {
+    let mut app = get_app_with(|state| state.user_storage_quota = 3).await;
+    let length = (2 * 1024 * 1024).to_string();
+
+    let request = tus_request(&mut app, "POST", "/files/tus").await
+        .header("upload-length", &length)
+        .header("upload-metadata", "filename dGVzdC50eHQ=,note_id MQ==")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::CREATED, response.status());
+
+    let location = header(&response, "location").to_string();
+
+    // the first upload hasn't received any data yet, but its whole length is already taken
+
+    let request = tus_request(&mut app, "POST", "/files/tus").await
+        .header("upload-length", &length)
+        .header("upload-metadata", "filename dGVzdC50eHQ=,note_id MQ==")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::INSUFFICIENT_STORAGE, response.status());
+
+    let request = tus_request(&mut app, "DELETE", &location).await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+}
+
+#[tokio::test]
+async fn files_link_post_missing_file() {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "POST", "/files/0/link").await
+        .header("content-type", "application/json")
+        .body(Body::from("{}"))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+}
+
+#[tokio::test]
+async fn public_dl_get_invalid_token() {
+    let app = get_app().await;
+
+    let request = Request::builder()
+        .uri("/public/dl/key1.eyJ1c2VyX2lkIjoxfQ.c2lnbmF0dXJl")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+}
+
+#[tokio::test]
+async fn tus_post_wrong_version() {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "POST", "/files/tus").await
+        .header("tus-resumable", "0.2.2")
+        .header("upload-length", "6")
+        .header("upload-metadata", "filename dGVzdC50eHQ=,note_id MQ==")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+    assert_eq!("1.0.0", header(&response, "tus-version"));
+}
+
+#[tokio::test]
+async fn tus_upload_resume_and_delete() {
+    let mut app = get_app().await;
+
+    // creating a 6 byte upload for test.txt in the note 1
+
+    let request = tus_request(&mut app, "POST", "/files/tus").await
+        .header("upload-length", "6")
+        .header("upload-metadata", "filename dGVzdC50eHQ=,note_id MQ==")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::CREATED, response.status());
+
+    let location = header(&response, "location").to_string();
+
+    // sending the first half
+
+    let request = tus_request(&mut app, "PATCH", &location).await
+        .header("content-type", "application/offset+octet-stream")
+        .header("upload-offset", "0")
+        .body(Body::from("abc"))
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+    assert_eq!("3", header(&response, "upload-offset"));
+
+    // resending the first half, as if the client didn't get the response
+
+    let request = tus_request(&mut app, "PATCH", &location).await
+        .header("content-type", "application/offset+octet-stream")
+        .header("upload-offset", "0")
+        .body(Body::from("abc"))
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::CONFLICT, response.status());
+
+    let request = tus_request(&mut app, "HEAD", &location).await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("3", header(&response, "upload-offset"));
+    assert_eq!("6", header(&response, "upload-length"));
+
+    // cancelling the upload
+
+    let request = tus_request(&mut app, "DELETE", &location).await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+
+    let request = tus_request(&mut app, "HEAD", &location).await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+}
+
+#[tokio::test]
+async fn notes_files_zip_get_missing_note() {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "GET", "/notes/0/files.zip").await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+}
+
+#[tokio::test]
+async fn files_dl_get_invalid_disposition() {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "GET", "/files/dl/hash?disposition=preview").await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+
+#[tokio::test]
+async fn files_thumb_get_invalid_size() {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "GET", "/files/dl/hash/thumb?w=0&h=4096").await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+
+#[tokio::test]
+async fn files_progress_get_before_upload() {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "GET", "/files/progress/test-upload").await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("text/event-stream", header(&response, "content-type"));
+
+    // the current state gets sent right away, even though the upload hasn't started yet
+
+    let frame = response.into_body().frame().await.unwrap().unwrap();
+    let event = String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap();
+
+    assert!(event.starts_with("event: progress\n"));
+}
+
+#[tokio::test]
+async fn files_progress_get_invalid_id() {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "GET", "/files/progress/upload.id").await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+
+/// Multipart body with a single file for the note 1
+fn file_body(content: &str) -> Body {
+    file_body_with(content, content.len(), None)
+}
+
+/// Same as `file_body`, but with a specific declared size and an optional checksum
+fn file_body_with(content: &str, file_size: usize, checksum: Option<&str>) -> Body {
+    let checksum = checksum.map(|c| format!(
+        "--boundary\r\n\
+        Content-Disposition: form-data; name=\"checksum\"\r\n\r\n\
+        {c}\r\n",
+    ));
+
+    Body::from(format!(
+        "--boundary\r\n\
+        Content-Disposition: form-data; name=\"note_id\"\r\n\r\n\
+        1\r\n\
+        --boundary\r\n\
+        Content-Disposition: form-data; name=\"file_size\"\r\n\r\n\
+        {file_size}\r\n\
+        {}\
+        --boundary\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"test.txt\"\r\n\r\n\
+        {content}\r\n\
+        --boundary--\r\n",
+        checksum.unwrap_or_default(),
+    ))
+}
+
+/// Uploads the body and checks that the only file in it was rejected because of its data
+async fn assert_invalid_file_data(body: Body) {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "POST", "/files").await
+        .header("content-type", "multipart/form-data; boundary=boundary")
+        .body(body)
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!("invalid values", body["data"][0]["error"]);
+    assert!(body["data"][0]["file"].is_null());
+}
+
+#[tokio::test]
+async fn files_post_shorter_than_file_size() {
+    assert_invalid_file_data(file_body_with("short", 10, None)).await;
+}
+
+#[tokio::test]
+async fn files_post_longer_than_file_size() {
+    assert_invalid_file_data(file_body_with("longer than declared", 4, None)).await;
+}
+
+#[tokio::test]
+async fn files_post_checksum_mismatch() {
+    let checksum = "0000000000000000000000000000000000000000000000000000000000000000";
+    assert_invalid_file_data(file_body_with("content", 7, Some(checksum))).await;
+}
+
+/// Uploads a multipart body made of `parts`, and returns the status along with the json body
+async fn post_parts(parts: &[String]) -> (StatusCode, Value) {
+    let mut app = get_app().await;
+    let body = format!("{}--boundary--\r\n", parts.concat());
+
+    let request = authorized_request(&mut app, "POST", "/files").await
+        .header("content-type", "multipart/form-data; boundary=boundary")
+        .body(Body::from(body))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+fn field_part(name: &str, value: &str) -> String {
+    format!("--boundary\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n")
+}
+
+/// `content_length` becomes the part's `Content-Length` header, if there is one
+fn file_part(name: &str, content: &str, content_length: Option<usize>) -> String {
+    let content_length = content_length.map(|l| format!("Content-Length: {l}\r\n")).unwrap_or_default();
+    format!("--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n{content_length}\r\n{content}\r\n")
+}
+
+#[tokio::test]
+async fn files_post_note_id_after_file() {
+    let (status, body) = post_parts(&[
+        field_part("file_size", "5"),
+        file_part("late.txt", "first", None),
+        field_part("note_id", "1"),
+    ]).await;
+
+    assert_eq!(StatusCode::CREATED, status);
+    assert_eq!("late.txt", body["data"][0]["name"]);
+    assert_eq!(5, body["data"][0]["file"]["size"].as_i64().unwrap());
+}
+
+#[tokio::test]
+async fn files_post_several_files() {
+    let (status, body) = post_parts(&[
+        field_part("note_id", "1"),
+        field_part("file_size", "5"),
+        file_part("first.txt", "first", None),
+        field_part("file_size", "6"),
+        file_part("second.txt", "second", None),
+    ]).await;
+
+    assert_eq!(StatusCode::CREATED, status);
+
+    let files = body["data"].as_array().unwrap();
+    assert_eq!(2, files.len());
+
+    for (file, name) in files.iter().zip(["first.txt", "second.txt"]) {
+        assert_eq!(name, file["name"]);
+        assert!(file["file"].is_object());
+        assert!(file["error"].is_null());
+    }
+}
+
+#[tokio::test]
+async fn files_post_some_files_failed() {
+    let (status, body) = post_parts(&[
+        field_part("note_id", "1"),
+        field_part("file_size", "5"),
+        file_part("ok.txt", "first", None),
+        field_part("file_size", "100"),
+        file_part("short.txt", "second", None),
+    ]).await;
+
+    assert_eq!(StatusCode::OK, status);
+
+    assert!(body["data"][0]["file"].is_object());
+    assert!(body["data"][0]["error"].is_null());
+
+    assert!(body["data"][1]["file"].is_null());
+    assert_eq!("invalid values", body["data"][1]["error"]);
+}
+
+#[tokio::test]
+async fn files_post_size_from_content_length() {
+    let (status, body) = post_parts(&[
+        field_part("note_id", "1"),
+        file_part("sized.txt", "content", Some(7)),
+    ]).await;
+
+    assert_eq!(StatusCode::CREATED, status);
+    assert_eq!(7, body["data"][0]["file"]["size"].as_i64().unwrap());
+}
+
+#[tokio::test]
+async fn files_post_wrong_content_length() {
+    let (status, body) = post_parts(&[
+        field_part("note_id", "1"),
+        file_part("sized.txt", "content", Some(3)),
+    ]).await;
+
+    assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+    assert_eq!("invalid values", body["data"][0]["error"]);
+}
+
+/// Posts the parts as a chunked body with no `Content-Length`, with the same idempotency key every time.
+/// The parts get sent with `boundary` instead of the usual one
+async fn post_chunked_parts_with_key(app: &mut Router, boundary: &str, parts: &[String]) -> Response {
+    let chunks: Vec<Result<String, std::io::Error>> = parts.iter()
+        .map(|p| p.replace("--boundary\r\n", &format!("--{boundary}\r\n")))
+        .chain([format!("--{boundary}--\r\n")])
+        .map(Ok)
+        .collect();
+
+    let request = authorized_request(app, "POST", "/files").await
+        .header("content-type", format!("multipart/form-data; boundary={boundary}"))
+        .header("idempotency-key", "files-post-test")
+        .body(Body::from_stream(futures_util::stream::iter(chunks)))
+        .unwrap();
+
+    app.clone().oneshot(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn files_post_chunked_replayed() {
+    let mut app = get_app().await;
+    let parts = [
+        field_part("note_id", "1"),
+        field_part("file_size", "7"),
+        file_part("replayed.txt", "content", None),
+    ];
+
+    let first = post_chunked_parts_with_key(&mut app, "boundary", &parts).await;
+    assert_eq!(StatusCode::CREATED, first.status());
+    assert!(first.headers().get("idempotent-replayed").is_none());
+
+    let repeat = post_chunked_parts_with_key(&mut app, "boundary", &parts).await;
+    assert_eq!(StatusCode::CREATED, repeat.status());
+    assert_eq!(repeat.headers()["idempotent-replayed"], "true");
+
+    // the repeat has to get the same file instead of creating another one
+
+    let first: Value = serde_json::from_slice(&first.into_body().collect().await.unwrap().to_bytes()).unwrap();
+    let repeat: Value = serde_json::from_slice(&repeat.into_body().collect().await.unwrap().to_bytes()).unwrap();
+
+    assert!(first["data"][0]["file"]["id"].is_number());
+    assert_eq!(first["data"][0]["file"]["id"], repeat["data"][0]["file"]["id"]);
+}
+
+#[tokio::test]
+async fn files_post_replayed_with_new_boundary() {
+    let mut app = get_app().await;
+    let parts = [
+        field_part("note_id", "1"),
+        field_part("file_size", "7"),
+        file_part("replayed.txt", "content", None),
+    ];
+
+    let first = post_chunked_parts_with_key(&mut app, "boundary", &parts).await;
+    assert_eq!(StatusCode::CREATED, first.status());
+
+    // clients usually pick a new boundary for every request, which doesn't make it a different request
+
+    let repeat = post_chunked_parts_with_key(&mut app, "another-boundary", &parts).await;
+    assert_eq!(StatusCode::CREATED, repeat.status());
+    assert_eq!(repeat.headers()["idempotent-replayed"], "true");
+
+    let other = [
+        field_part("note_id", "1"),
+        field_part("file_size", "7"),
+        file_part("replayed.txt", "changed", None),
+    ];
+
+    let reused = post_chunked_parts_with_key(&mut app, "boundary", &other).await;
+    assert_eq!(StatusCode::CONFLICT, reused.status());
+}
+
+#[tokio::test]
+async fn files_post_infected() {
+    let clamd_addr = start_fake_clamd().await;
+    let mut app = get_app_with(|state| state.scanner = Arc::new(Scanner::new(Some(clamd_addr), ScanPolicy::Closed, 5000))).await;
+
+    let request = authorized_request(&mut app, "POST", "/files").await
+        .header("content-type", "multipart/form-data; boundary=boundary")
+        .body(file_body(EICAR))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!("file is infected", body["data"][0]["error"]);
+}
+
+#[tokio::test]
+async fn files_post_scanner_unavailable_fail_closed() {
+    let clamd_addr = unused_addr().await;
+    let mut app = get_app_with(|state| state.scanner = Arc::new(Scanner::new(Some(clamd_addr), ScanPolicy::Closed, 5000))).await;
+
+    let request = authorized_request(&mut app, "POST", "/files").await
+        .header("content-type", "multipart/form-data; boundary=boundary")
+        .body(file_body("clean"))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+}
@@ -0,0 +1,76 @@
+use axum::{body::Body, http::{Request, StatusCode}};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+use super::{get_app, get_app_with};
+
+#[tokio::test]
+async fn healthz_get() {
+    let app = get_app().await;
+
+    let request = Request::builder()
+        .uri("/healthz")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+#[tokio::test]
+async fn readyz_get() {
+    let app = get_app().await;
+
+    let request = Request::builder()
+        .uri("/readyz")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::OK, response.status());
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    let exp = json!({
+        "success": true,
+        "error": None::<()>,
+        "data": {
+            "auth": { "ready": true, "error": None::<()> },
+            "data": { "ready": true, "error": None::<()> },
+        },
+    });
+
+    assert_eq!(body, exp);
+}
+
+#[tokio::test]
+async fn metrics_get() {
+    let app = get_app_with(|state| state.metrics_port = None).await;
+
+    // an unauthorized request, just to make sure that there's at least one recorded http request
+
+    let request = Request::builder()
+        .uri("/tags")
+        .body(Body::empty())
+        .unwrap();
+
+    app.clone().oneshot(request).await.unwrap();
+
+    let request = Request::builder()
+        .uri("/metrics")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::OK, response.status());
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+
+    assert!(body.contains("http_requests_total"));
+}
@@ -1,14 +1,29 @@
-use axum::{body::Body, http::Request, Router};
+use std::collections::HashMap;
+
+use axum::{body::Body, http::{request::Builder, Request}, Router};
 use serde_json::{json, Value};
 use tower::Service;
 
-use crate::{load_state, routes::get_router};
+use crate::{load_state, routes::get_router, types::AppState};
 
+mod auth;
+mod clamd;
+mod files;
+mod health;
+mod notes;
+mod shelves;
 mod tags;
+mod ws;
 
 async fn get_app() -> Router {
+    get_app_with(|_| ()).await
+}
+
+/// Same as `get_app`, but lets the test change the state before building the router
+async fn get_app_with(modify: impl FnOnce(&mut AppState)) -> Router {
     let mut state = load_state().await.expect("Could not load the app state");
     state.log_level = tracing::Level::ERROR;
+    modify(&mut state);
     get_router(&state).expect("Could not get the app router")
 }
 
@@ -16,43 +31,77 @@ fn new_body(json: Value) -> Body {
     Body::from(serde_json::to_vec(&json).unwrap())
 }
 
+/// Name of the header that the client fingerprint is sent in
+fn fingerprint_header() -> String {
+    dotenvy::var("FINGERPRINT_HEADER").expect("Could not get the fingerprint header name")
+}
+
 /// Get a pair of cookie tokens for use in other requests
 async fn authorize(app: &mut Router) -> (String, String) {
+    authorize_with_fingerprint(app, "").await
+}
+
+/// Same as `authorize`, but logs in with a specific client fingerprint
+async fn authorize_with_fingerprint(app: &mut Router, fingerprint: &str) -> (String, String) {
+    let cookies = login(app, fingerprint).await;
+
+    match (cookies.get("at"), cookies.get("rt")) {
+        (Some(at), Some(rt)) => (at.clone(), rt.clone()),
+        _ => panic!("Did not get the expected cookies, expected keys \"at\" and \"rt\""),
+    }
+}
+
+/// Builds a request that is authorized with cookies and has the csrf header
+async fn authorized_request(app: &mut Router, method: &str, uri: &str) -> Builder {
+    let cookies = login(app, "").await;
+    let csrf_cookie = &cookies["csrf"];
+
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("cookie", format!("{}; {}", cookies["at"], csrf_cookie))
+        .header(dotenvy::var("CSRF_HEADER").unwrap(), csrf_cookie.split_once('=').unwrap().1)
+}
+
+/// Logs in and returns all of the received cookies as a map of cookie keys to `key=value` pairs
+async fn login(app: &mut Router, fingerprint: &str) -> HashMap<String, String> {
+    login_set_cookies(app, fingerprint)
+        .await
+        .into_iter()
+        .map(|v| {
+            let cookie = v
+                .split(';')
+                .next()
+                .expect("Could not parse an auth cookies value")
+                .to_string();
+
+            let key = cookie.split('=').next().unwrap_or_default().to_string();
+            (key, cookie)
+        })
+        .collect()
+}
+
+/// Logs in and returns the raw values of the received `set-cookie` headers, along with all of their attributes
+async fn login_set_cookies(app: &mut Router, fingerprint: &str) -> Vec<String> {
     let request = Request::builder()
         .method("POST")
         .uri("/login")
         .header("content-type", "application/json")
+        .header(fingerprint_header(), fingerprint)
         .body(new_body(
             json!({ "email": "nexochan@mail.ru", "password": "1234" }),
         ))
         .expect("Could not build an auth request");
 
-    let mut response = app
+    let response = app
         .call(request)
         .await
         .expect("Could not get an auth response");
 
-    let mut cookie_values = response
-        .headers_mut()
+    response
+        .headers()
         .get_all("set-cookie")
         .into_iter()
-        .map(|v| v
-            .to_str()
-            .expect("Could not convert auth header value to string")
-            .split(';')
-            .next()
-            .expect("Could not parse an auth cookies value")
-            .to_string()
-        );
-
-    let cookie_1 = cookie_values.next();
-    let cookie_2 = cookie_values.next();
-
-    let (access_token, refresh_token) = match (cookie_1, cookie_2) {
-        (Some(at), Some(rt)) if at.starts_with("at=") && rt.starts_with("rt=") => (at, rt),
-        (Some(rt), Some(at)) if rt.starts_with("rt=") && at.starts_with("at=") => (at, rt),
-        _ => panic!("Did not get the expected cookies, expected keys \"at\" and \"rt\""),
-    };
-
-    (access_token, refresh_token)
+        .map(|v| v.to_str().expect("Could not convert auth header value to string").to_string())
+        .collect()
 }
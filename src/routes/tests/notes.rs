@@ -0,0 +1,69 @@
+use axum::{body::Body, http::StatusCode, response::Response, Router};
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use tower::Service;
+
+use super::{authorized_request, get_app, new_body};
+
+async fn into_json(response: Response) -> Value {
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Creates a note to be changed by the test, and returns its id and version
+async fn create_note(app: &mut Router) -> (i64, String) {
+    let request = authorized_request(app, "POST", "/notes").await
+        .header("content-type", "application/json")
+        .body(new_body(json!({ "title": "versioned", "text": "original" })))
+        .unwrap();
+
+    let response = app.call(request).await.unwrap();
+    assert_eq!(StatusCode::CREATED, response.status());
+
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    let body = into_json(response).await;
+
+    (body["data"]["id"].as_i64().unwrap(), etag)
+}
+
+async fn patch_note(app: &mut Router, note_id: i64, if_match: &str, text: &str) -> Response {
+    let request = authorized_request(app, "PATCH", &format!("/notes/{note_id}")).await
+        .header("content-type", "application/json")
+        .header("if-match", if_match)
+        .body(new_body(json!({ "title": "versioned", "text": text })))
+        .unwrap();
+
+    app.call(request).await.unwrap()
+}
+
+async fn delete_note(app: &mut Router, note_id: i64) {
+    let request = authorized_request(app, "DELETE", &format!("/notes/{note_id}")).await
+        .body(Body::empty())
+        .unwrap();
+
+    assert_eq!(StatusCode::OK, app.call(request).await.unwrap().status());
+}
+
+#[tokio::test]
+async fn notes_patch_if_match() {
+    let mut app = get_app().await;
+    let (note_id, etag) = create_note(&mut app).await;
+
+    let updated = patch_note(&mut app, note_id, &etag, "updated").await;
+    assert_eq!(StatusCode::OK, updated.status());
+
+    let new_etag = updated.headers()["etag"].to_str().unwrap().to_string();
+    assert_ne!(etag, new_etag);
+
+    // the first version is stale now, so the same change can't be made with it again
+
+    let stale = patch_note(&mut app, note_id, &etag, "overwritten").await;
+    assert_eq!(StatusCode::PRECONDITION_FAILED, stale.status());
+    assert_eq!(new_etag, stale.headers()["etag"].to_str().unwrap());
+
+    let body = into_json(stale).await;
+    assert_eq!("precondition failed", body["error"]);
+    assert_eq!("updated", body["data"]["text"]);
+
+    delete_note(&mut app, note_id).await;
+}
@@ -0,0 +1,42 @@
+use axum::{body::Body, http::StatusCode};
+use http_body_util::BodyExt;
+use serde_json::Value;
+use tower::ServiceExt;
+
+use super::{authorized_request, get_app};
+
+#[tokio::test]
+async fn shelf_get_etag() {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "GET", "/shelf").await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(StatusCode::OK, response.status());
+    assert!(response.headers().contains_key("etag"));
+}
+
+#[tokio::test]
+async fn shelf_patch_stale_version() {
+    let mut app = get_app().await;
+
+    let request = authorized_request(&mut app, "PATCH", "/shelf").await
+        .header("content-type", "application/json")
+        .header("if-match", "\"stale\"")
+        .body(Body::from(r#"{"text":"overwritten"}"#))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+
+    // the current shelf comes back, so that the client can merge its changes into it
+
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(etag, format!("\"{}-{}\"", body["data"]["times_edited"], body["data"]["last_edited"]));
+}
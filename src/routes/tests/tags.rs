@@ -1,9 +1,9 @@
-use axum::{body::Body, http::{Request, StatusCode}};
+use axum::{body::Body, http::{Request, StatusCode}, response::Response, Router};
 use serde_json::{json, Value};
-use tower::ServiceExt;
+use tower::{Service, ServiceExt};
 use http_body_util::BodyExt;
 
-use super::{get_app, authorize};
+use super::{authorize, authorized_request, get_app, new_body};
 
 #[tokio::test]
 async fn tags_get() {
@@ -30,3 +30,32 @@ async fn tags_get() {
 
     assert_eq!(body, exp);
 }
+
+/// Posts a tag with the same idempotency key every time
+async fn post_tag_with_key(app: &mut Router, body: Value) -> Response {
+    let request = authorized_request(app, "POST", "/tags").await
+        .header("content-type", "application/json")
+        .header("idempotency-key", "tags-post-test")
+        .body(new_body(body))
+        .unwrap();
+
+    app.call(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn tags_post_idempotency_key_reuse() {
+    let mut app = get_app().await;
+
+    // the body is invalid so that the test doesn't create any tags, but client errors get stored all the same
+
+    let first = post_tag_with_key(&mut app, json!({})).await;
+    assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, first.status());
+    assert!(first.headers().get("idempotent-replayed").is_none());
+
+    let repeat = post_tag_with_key(&mut app, json!({})).await;
+    assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, repeat.status());
+    assert_eq!(repeat.headers()["idempotent-replayed"], "true");
+
+    let different = post_tag_with_key(&mut app, json!({ "name": 39 })).await;
+    assert_eq!(StatusCode::CONFLICT, different.status());
+}
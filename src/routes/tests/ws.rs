@@ -0,0 +1,140 @@
+use std::{future::IntoFuture, net::SocketAddr, time::Duration};
+
+use axum::{body::Body, http::{Request, StatusCode}, Router};
+use futures_util::StreamExt;
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, protocol::frame::coding::CloseCode, Error, Message};
+use tower::ServiceExt;
+
+use super::{authorized_request, fingerprint_header, get_app, login, new_body};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Serves the app on a random local port, since WebSockets need a real connection. Returns the port
+async fn serve(app: Router) -> u16 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).into_future());
+
+    port
+}
+
+#[tokio::test]
+async fn ws_get_hello() {
+    let mut app = get_app().await;
+    let cookies = login(&mut app, "").await;
+    let port = serve(app).await;
+
+    let mut request = format!("ws://127.0.0.1:{port}/ws").into_client_request().unwrap();
+    request.headers_mut().insert("cookie", cookies["at"].parse().unwrap());
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+    let message = socket.next().await.unwrap().unwrap();
+    let message: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+
+    assert_eq!("hello", message["type"]);
+    assert!(message["data"]["epoch"].is_string());
+}
+
+#[tokio::test]
+async fn ws_get_foreign_origin() {
+    let mut app = get_app().await;
+    let cookies = login(&mut app, "").await;
+    let port = serve(app).await;
+
+    let mut request = format!("ws://127.0.0.1:{port}/ws").into_client_request().unwrap();
+    request.headers_mut().insert("cookie", cookies["at"].parse().unwrap());
+    request.headers_mut().insert("origin", "https://example.com".parse().unwrap());
+
+    match tokio_tungstenite::connect_async(request).await {
+        Err(Error::Http(response)) => assert_eq!(403, response.status()),
+        res => panic!("Expected the connection to be refused, got {res:?}"),
+    }
+}
+
+/// Opens a WebSocket with the access cookie and skips the `hello` message
+async fn connect(port: u16, access_cookie: &str) -> Socket {
+    let mut request = format!("ws://127.0.0.1:{port}/ws").into_client_request().unwrap();
+    request.headers_mut().insert("cookie", access_cookie.parse().unwrap());
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    socket.next().await.unwrap().unwrap();
+
+    socket
+}
+
+async fn next_message(socket: &mut Socket) -> Message {
+    tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("Did not get a message in time")
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn ws_get_tag_events() {
+    let mut app = get_app().await;
+    let cookies = login(&mut app, "").await;
+    let port = serve(app.clone()).await;
+    let mut socket = connect(port, &cookies["at"]).await;
+
+    let request = authorized_request(&mut app, "POST", "/tags").await
+        .header("content-type", "application/json")
+        .body(new_body(json!({ "name": "ws test tag" })))
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::CREATED, response.status());
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    let tag_id = body["data"]["id"].as_i64().unwrap();
+
+    let event: Value = serde_json::from_str(next_message(&mut socket).await.to_text().unwrap()).unwrap();
+
+    assert_eq!("tag_created", event["type"]);
+    assert_eq!(tag_id, event["data"]["id"].as_i64().unwrap());
+    assert_eq!("ws test tag", event["data"]["name"]);
+
+    // cleaning up the tag, which is also an event
+
+    let request = authorized_request(&mut app, "DELETE", &format!("/tags/{tag_id}")).await
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+
+    let event: Value = serde_json::from_str(next_message(&mut socket).await.to_text().unwrap()).unwrap();
+
+    assert_eq!("tag_deleted", event["type"]);
+    assert_eq!(tag_id, event["data"]["id"].as_i64().unwrap());
+}
+
+#[tokio::test]
+async fn ws_get_closed_on_logout() {
+    let mut app = get_app().await;
+    let cookies = login(&mut app, "").await;
+    let port = serve(app.clone()).await;
+    let mut socket = connect(port, &cookies["at"]).await;
+
+    let request = Request::builder()
+        .uri("/logout")
+        .header("cookie", &cookies["at"])
+        .header(fingerprint_header(), "")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+
+    match next_message(&mut socket).await {
+        Message::Close(Some(frame)) => assert_eq!(CloseCode::Policy, frame.code),
+        message => panic!("Expected the connection to be closed, got {message:?}"),
+    }
+}
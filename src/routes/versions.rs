@@ -0,0 +1,64 @@
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+
+use crate::error::ResError;
+use crate::proto::{notes::Note, shelves::Shelf};
+use crate::types::{new_err_data_res, Json, ResultBody};
+
+/// Something that can be updated conditionally with `If-Match`
+pub trait Versioned {
+    /// The entity tag is `"{times_edited}-{last_edited}"`, so clients can also make it themselves from lists that don't come with one
+    fn etag(&self) -> String;
+}
+
+impl Versioned for Note {
+    fn etag(&self) -> String {
+        format!("\"{}-{}\"", self.times_edited, self.last_edited)
+    }
+}
+
+impl Versioned for Shelf {
+    fn etag(&self) -> String {
+        format!("\"{}-{}\"", self.times_edited, self.last_edited)
+    }
+}
+
+pub fn if_match(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok())
+}
+
+/// Gets `last_edited` out of the first tag in `If-Match` that looks like one of ours,
+/// so that the current version can be looked up without going through every note
+pub fn if_match_last_edited(if_match: &str) -> Option<i64> {
+    if_match.split(',')
+        .map(|v| v.trim())
+        .find_map(|v| v.strip_prefix('"')?.strip_suffix('"')?.split_once('-')?.1.parse().ok())
+}
+
+/// Checks `If-Match` against the current version. If none of the tags match, returns a 412 response with the current version,
+/// so that the client can merge its changes into it. Weak tags never match, as required by RFC 9110
+pub fn precondition_failed<T: Versioned + Serialize>(if_match: &str, current: T) -> Option<Response> {
+    let etag = current.etag();
+
+    if if_match.split(',').map(|v| v.trim()).any(|v| v == "*" || v == etag) {
+        return None;
+    }
+
+    let (status_code, response_msg, internal_msg) = ResError::PreconditionFailed(
+        format!("If-Match {if_match} does not match the current version {etag}"),
+    ).into_parts();
+
+    let (status_code, body) = new_err_data_res(status_code, response_msg, internal_msg, Some(current));
+
+    Some((status_code, [(header::ETAG, etag)], body).into_response())
+}
+
+/// Same as `new_ok_res`, but with the `ETag` header
+pub fn new_versioned_res<T: Versioned + Serialize>(code: StatusCode, data: T) -> Result<Response, ResError> {
+    Ok((
+        code,
+        [(header::ETAG, data.etag())],
+        Json(ResultBody { success: true, error: None, data: Some(data) }),
+    ).into_response())
+}
@@ -0,0 +1,181 @@
+use std::{pin::pin, time::{Duration, Instant}};
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap};
+use axum::response::Response;
+use axum::{routing::get, Extension, Router};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+use utoipa::OpenApi;
+
+use crate::events::Subscription;
+use crate::token_cache::{token_expires_in, token_hash, TokenHash};
+use crate::{error::ResError, types::{AccessToken, AppState, ExRes400, ExRes401, ExRes5XX}};
+
+/// How often the server pings the client. A connection that doesn't answer for two intervals gets closed
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+pub fn get_router(state: &AppState) -> Router {
+    Router::new()
+        .route("/", get(ws_get))
+        .with_state(state.clone())
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(ws_get),
+    security(("access_token" = []), ("bearer_token" = [])),
+)]
+pub struct Api;
+
+/// Access token that the connection has been opened with. The connection only lives as long as the token does
+struct Session {
+    token_hash: TokenHash,
+    expires_at: tokio::time::Instant,
+    logouts: broadcast::Receiver<TokenHash>,
+}
+
+/// helper struct that the `ws_get` url query deserializes into
+#[derive(Debug, Default, Deserialize)]
+struct WsQuery {
+    epoch: Option<String>,
+    since: Option<u64>,
+}
+
+/// Get change notifications
+///
+/// Opens a WebSocket that receives the user's changes to notes, tags, files and the shelf, as JSON text messages
+/// like `{"seq": 5, "type": "note_updated", "data": {...}}`. Only the changes made through this gateway are sent.
+///
+/// The first message is `{"type": "hello", "data": {"epoch": "...", "seq": 4}}`. To reconnect without losing anything,
+/// pass the `epoch` along with the `seq` of the last received event as `since`, and the missed events will be sent right after `hello`.
+/// If they are no longer available, or if the connection falls too far behind, a `{"type": "resync"}` message is sent instead,
+/// after which everything should be fetched again.
+///
+/// The server sends a ping every 30 seconds, and closes the connection if the client doesn't answer.
+/// It also closes the connection with the code 1012 when shutting down, after which the client should reconnect.
+/// The connection gets closed with the code 1008 once its access token expires or gets logged out, after which the client should reconnect with a new token
+#[utoipa::path(
+    get, path = "",
+    params(
+        ("epoch" = Option<String>, Query, description = "`epoch` from the previous connection's `hello` message"),
+        ("since" = Option<u64>, Query, description = "`seq` of the last event that the previous connection has received"),
+    ),
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol"),
+        ExRes400, ExRes401, ExRes5XX,
+        (status = 403, description = "The request came from an origin other than the Frontend"),
+    ),
+)]
+#[tracing::instrument(skip(state, access_token, headers, ws), err(level = tracing::Level::DEBUG))]
+async fn ws_get(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<i32>,
+    Extension(AccessToken(access_token)): Extension<AccessToken>,
+    Query(query): Query<WsQuery>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ResError> {
+
+    // browsers don't apply CORS to WebSockets, but they send cookies along, so other sites would be able to connect.
+    // clients that aren't browsers don't send the origin at all
+
+    let origin = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok());
+
+    if origin.is_some_and(|o| state.frontend_url.trim_end_matches('/') != o) {
+        return Err(ResError::Forbidden(format!("WebSocket connection from a foreign origin: {origin:?}")));
+    }
+
+    let since = match (query.epoch, query.since) {
+        (Some(epoch), Some(since)) => Some((epoch, since)),
+        (None, None) => None,
+        _ => return Err(ResError::InvalidFields("epoch and since must be specified together".into())),
+    };
+
+    // tokens without a readable expiry are assumed to live for as long as the access cookie does
+
+    let expires_in = token_expires_in(&access_token).unwrap_or(Duration::from_secs(state.access_token_ttl.max(0) as u64));
+
+    let session = Session {
+        token_hash: token_hash(&access_token),
+        expires_at: tokio::time::Instant::now() + expires_in,
+        logouts: state.events.subscribe_logouts(),
+    };
+
+    Ok(ws.on_upgrade(move |socket| async move {
+        let subscription = state.events.subscribe(user_id, since.as_ref().map(|(e, s)| (e.as_str(), *s)));
+
+        if let Err(e) = handle_socket(state, socket, subscription, session).await {
+            debug!(user_id, "WebSocket connection closed: {e}");
+        }
+    }))
+}
+
+async fn handle_socket(state: AppState, socket: WebSocket, subscription: Subscription, session: Session) -> Result<(), axum::Error> {
+    let Subscription { mut rx, epoch, seq, missed } = subscription;
+    let Session { token_hash, expires_at, mut logouts } = session;
+    let (mut sender, mut receiver) = socket.split();
+
+    sender.send(Message::Text(json!({ "type": "hello", "data": { "epoch": epoch, "seq": seq } }).to_string())).await?;
+
+    match missed {
+        Some(missed) => for envelope in missed {
+            sender.send(Message::Text(json!(*envelope).to_string())).await?;
+        },
+        None => sender.send(Message::Text(json!({ "type": "resync" }).to_string())).await?,
+    }
+
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+    let mut shutdown = pin!(state.shutdown.clone().wait_for_start());
+    let mut expiry = pin!(tokio::time::sleep_until(expires_at));
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Ok(envelope) => sender.send(Message::Text(json!(*envelope).to_string())).await?,
+                Err(RecvError::Lagged(_)) => sender.send(Message::Text(json!({ "type": "resync" }).to_string())).await?,
+                Err(RecvError::Closed) => break,
+            },
+            message = receiver.next() => match message {
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => last_seen = Instant::now(),
+                Some(Err(e)) => return Err(e),
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > 2 * HEARTBEAT_INTERVAL {
+                    break;
+                }
+
+                sender.send(Message::Ping(Vec::new())).await?;
+            },
+            // missing some logouts means that this connection's token might have been among them
+
+            logout = logouts.recv() => match logout {
+                Ok(hash) if hash != token_hash => (),
+                Err(RecvError::Closed) => break,
+                _ => {
+                    let frame = CloseFrame { code: close_code::POLICY, reason: "The access token has been logged out".into() };
+                    sender.send(Message::Close(Some(frame))).await?;
+                    break;
+                },
+            },
+            _ = &mut expiry => {
+                let frame = CloseFrame { code: close_code::POLICY, reason: "The access token has expired".into() };
+                sender.send(Message::Close(Some(frame))).await?;
+                break;
+            },
+            _ = &mut shutdown => {
+                let frame = CloseFrame { code: close_code::RESTART, reason: "The service is shutting down".into() };
+                sender.send(Message::Close(Some(frame))).await?;
+                break;
+            },
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::{str::FromStr, time::Duration};
+
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+use tracing::warn;
+
+use crate::error::ResError;
+
+/// Max size of a single chunk in the INSTREAM protocol. clamd closes the connection if a chunk is bigger than its `StreamMaxLength`
+const MAX_SCAN_CHUNK_SIZE: usize = 64 * 1024;
+/// Max length of clamd's reply
+const MAX_REPLY_LEN: u64 = 4096;
+
+/// Defines what happens to an upload when the file can't be scanned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanPolicy {
+    /// The upload goes through without being scanned
+    Open,
+    /// The upload gets rejected
+    Closed,
+}
+
+impl FromStr for ScanPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(Self::Open),
+            "closed" => Ok(Self::Closed),
+            _ => Err(anyhow::anyhow!("Invalid scan policy: {s}, expected one of: open, closed")),
+        }
+    }
+}
+
+/// Scans uploaded files with clamd (ClamAV daemon) over its INSTREAM protocol. Scanning is disabled if there is no address
+#[derive(Debug, Clone)]
+pub struct Scanner {
+    addr: Option<String>,
+    policy: ScanPolicy,
+    timeout: Duration,
+}
+
+impl Scanner {
+    /// `timeout` is in milliseconds, and applies to each step of talking to clamd separately
+    pub fn new(addr: Option<String>, policy: ScanPolicy, timeout: u64) -> Self {
+        Self { addr, policy, timeout: Duration::from_millis(timeout) }
+    }
+
+    /// Opens a new scan. Returns `None` if scanning is disabled, or if clamd is unreachable and the policy is open
+    pub async fn start(&self) -> Result<Option<Scan>, ResError> {
+        let Some(addr) = &self.addr else {
+            return Ok(None);
+        };
+
+        let connect = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.write_all(b"zINSTREAM\0").await?;
+            Ok::<_, std::io::Error>(stream)
+        };
+
+        match tokio::time::timeout(self.timeout, connect).await {
+            Ok(Ok(stream)) => Ok(Some(Scan { stream, timeout: self.timeout, policy: self.policy })),
+            Ok(Err(e)) => self.policy.failed(format!("Could not connect to clamd: {e}")).map(|_| None),
+            Err(_) => self.policy.failed("Timed out connecting to clamd".into()).map(|_| None),
+        }
+    }
+}
+
+impl ScanPolicy {
+    fn failed(self, msg: String) -> Result<(), ResError> {
+        match self {
+            Self::Open => {
+                warn!("{msg}, letting the file through without scanning it");
+                Ok(())
+            },
+            Self::Closed => Err(ResError::ServiceUnavailable(msg)),
+        }
+    }
+}
+
+/// A single file that is being scanned. The data has to be written as it comes, and then the verdict is received with `finish`
+#[derive(Debug)]
+pub struct Scan {
+    stream: TcpStream,
+    timeout: Duration,
+    policy: ScanPolicy,
+}
+
+impl Scan {
+    /// Sends the data to clamd. Returns `Ok(None)` if sending has failed, but the policy lets the file through anyway
+    pub async fn write(mut self, data: &[u8]) -> Result<Option<Self>, ResError> {
+        let send = async {
+            for chunk in data.chunks(MAX_SCAN_CHUNK_SIZE) {
+                self.stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+                self.stream.write_all(chunk).await?;
+            }
+
+            Ok::<_, std::io::Error>(())
+        };
+
+        // clamd closes the connection on its side once the file is over its size limit
+
+        match tokio::time::timeout(self.timeout, send).await {
+            Ok(Ok(())) => Ok(Some(self)),
+            Ok(Err(e)) => self.policy.failed(format!("Could not send data to clamd: {e}")).map(|_| None),
+            Err(_) => self.policy.failed("Timed out sending data to clamd".into()).map(|_| None),
+        }
+    }
+
+    /// Ends the stream and waits for the verdict. Fails with `ResError::Infected` if clamd has found something
+    pub async fn finish(mut self) -> Result<(), ResError> {
+        let receive = async {
+            self.stream.write_all(&[0; 4]).await?;
+
+            let mut reply = Vec::new();
+            (&mut self.stream).take(MAX_REPLY_LEN).read_to_end(&mut reply).await?;
+
+            Ok::<_, std::io::Error>(reply)
+        };
+
+        let reply = match tokio::time::timeout(self.timeout, receive).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(e)) => return self.policy.failed(format!("Could not get a reply from clamd: {e}")),
+            Err(_) => return self.policy.failed("Timed out waiting for a reply from clamd".into()),
+        };
+
+        // the reply looks like `stream: OK`, `stream: {signature} FOUND` or `{message} ERROR`
+
+        let reply = String::from_utf8_lossy(&reply);
+        let reply = reply.trim_end_matches(['\0', '\n']).trim_start_matches("stream:").trim();
+
+        if reply == "OK" {
+            return Ok(());
+        }
+
+        match reply.strip_suffix(" FOUND") {
+            Some(signature) => Err(ResError::Infected(format!("clamd has found {signature}"))),
+            None => self.policy.failed(format!("clamd could not scan the file: {reply}")),
+        }
+    }
+}
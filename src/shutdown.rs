@@ -0,0 +1,126 @@
+use std::{collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+use axum::{body::{Body, HttpBody}, extract::{Request, State}, middleware::Next, response::Response};
+use futures_util::StreamExt;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::types::AppState;
+
+/// Keeps track of the shutdown progress and of the requests that are still being handled
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    /// Gets cancelled once the shutdown signal is received
+    started: CancellationToken,
+    /// Gets cancelled once the grace period is over, which makes the remaining file streams stop
+    streams: CancellationToken,
+    next_id: AtomicU64,
+    in_flight: Mutex<HashMap<u64, (String, Instant)>>,
+}
+
+/// Removes the request from the in-flight list once dropped
+#[derive(Debug)]
+pub struct InFlightGuard {
+    shutdown: Arc<Shutdown>,
+    id: u64,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.shutdown.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Shutdown {
+    pub fn track(self: &Arc<Self>, description: String) -> InFlightGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.lock().unwrap().insert(id, (description, Instant::now()));
+
+        InFlightGuard { shutdown: self.clone(), id }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started.is_cancelled()
+    }
+
+    /// Token that long-running file streams should stop on
+    pub fn stream_token(&self) -> CancellationToken {
+        self.streams.clone()
+    }
+
+    /// Completes once SIGTERM or SIGINT is received
+    pub async fn wait_for_signal(self: Arc<Self>) {
+        let ctrl_c = async {
+            tokio::signal::ctrl_c().await.expect("Could not listen for SIGINT");
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("Could not listen for SIGTERM")
+                .recv()
+                .await;
+        };
+
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => (),
+            _ = terminate => (),
+        }
+
+        info!("Shutdown signal received, waiting for {} in-flight requests", self.in_flight.lock().unwrap().len());
+        self.started.cancel();
+    }
+
+    /// Completes once the shutdown signal is received
+    pub async fn wait_for_start(self: Arc<Self>) {
+        self.started.cancelled().await;
+    }
+
+    /// Completes once the grace period after the shutdown signal is over
+    pub async fn grace_period_over(&self, grace_period: Duration) {
+        self.started.cancelled().await;
+        tokio::time::sleep(grace_period).await;
+    }
+
+    pub fn cancel_streams(&self) {
+        self.streams.cancel();
+    }
+
+    pub fn log_in_flight(&self) {
+        let in_flight = self.in_flight.lock().unwrap();
+
+        if in_flight.is_empty() {
+            return info!("All in-flight requests have finished");
+        }
+
+        for (description, started_at) in in_flight.values() {
+            warn!("Request was still in flight on exit: {description} (running for {:?})", started_at.elapsed());
+        }
+    }
+}
+
+/// Tracks the request until its response body is fully sent, which for file downloads happens long after the handler returns
+pub async fn in_flight_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let guard = state.shutdown.track(format!("{} {}", req.method(), req.uri()));
+    let response = next.run(req).await;
+
+    // responses with an exact size are already complete, so there is no need to wait for the body
+
+    if response.body().size_hint().exact().is_some() {
+        return response;
+    }
+
+    response.map(|body| Body::from_stream(
+        body.into_data_stream().map(move |chunk| {
+            let _guard = &guard;
+            chunk
+        })
+    ))
+}
@@ -0,0 +1,83 @@
+use std::{sync::OnceLock, time::{Duration, Instant}};
+
+use axum::{extract::{MatchedPath, Request}, http::header, middleware::Next, response::{IntoResponse, Response}, routing::get, Router};
+use metrics::{counter, histogram};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+/// Histogram buckets (in seconds) for both HTTP and gRPC latencies
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+static PROMETHEUS_HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Installs the global metrics recorder. Can be called multiple times (e.g. in tests), the recorder only gets installed once
+fn get_prometheus_handle() -> &'static PrometheusHandle {
+    PROMETHEUS_HANDLE.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets(LATENCY_BUCKETS)
+            .expect("Could not set the metrics histogram buckets")
+            .install_recorder()
+            .expect("Could not install the metrics recorder")
+    })
+}
+
+/// Router with the `/metrics` route, which can be either merged into the main router or served on a separate port
+pub fn get_router() -> Router {
+    get_prometheus_handle();
+    Router::new().route("/metrics", get(metrics_get))
+}
+
+async fn metrics_get() -> impl IntoResponse {
+    let handle = get_prometheus_handle();
+    handle.run_upkeep();
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        handle.render(),
+    )
+}
+
+/// Records the count and latency of HTTP requests, labeled by the route template instead of the actual path,
+/// so that ids in the path don't blow up the amount of label values
+pub async fn http_metrics_middleware(
+    matched_path: Option<MatchedPath>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = matched_path
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".into());
+
+    let response = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", response.status().as_u16().to_string()),
+    ];
+
+    counter!("http_requests_total", &labels).increment(1);
+    histogram!("http_request_duration_seconds", &labels).record(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Records the count, latency and result code of a gRPC call
+pub fn record_grpc_call(method: &'static str, code: tonic::Code, elapsed: Duration) {
+    let labels = [
+        ("method", method.to_string()),
+        ("code", format!("{code:?}")),
+    ];
+
+    counter!("grpc_client_calls_total", &labels).increment(1);
+    histogram!("grpc_client_call_duration_seconds", &labels).record(elapsed.as_secs_f64());
+}
+
+pub fn record_uploaded_bytes(bytes: usize) {
+    counter!("file_upload_bytes_total").increment(bytes as u64);
+}
+
+pub fn record_downloaded_bytes(bytes: usize) {
+    counter!("file_download_bytes_total").increment(bytes as u64);
+}
@@ -0,0 +1,197 @@
+use std::{path::PathBuf, sync::Mutex, time::SystemTime};
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use tracing::{debug, warn};
+
+use crate::error::ResError;
+
+/// Local disk cache for generated thumbnails. Each thumbnail is stored as `{file_hash}_{width}x{height}.{extension}`.
+/// File hashes never change, so the thumbnails never get outdated, and there is nothing to invalidate.
+/// Once the cache grows past `max_size`, the oldest thumbnails get removed
+#[derive(Debug)]
+pub struct ThumbnailCache {
+    dir: PathBuf,
+    /// Max size of a file that a thumbnail can be made from (in bytes)
+    pub max_input_size: u64,
+    /// Max total size of the cached thumbnails (in bytes). 0 means there is no limit
+    max_size: u64,
+    /// Total size of the cached thumbnails, as far as this instance knows. Gets counted again whenever the cache gets cleaned up
+    size: Mutex<u64>,
+}
+
+/// Format of a thumbnail. Images with transparency become WebP, since JPEG can't store it, and the rest become JPEG
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    Jpeg,
+    WebP,
+}
+
+impl ThumbnailFormat {
+    const ALL: [Self; 2] = [Self::Jpeg, Self::WebP];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::WebP => "webp",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::WebP => "image/webp",
+        }
+    }
+}
+
+impl ThumbnailCache {
+    /// `max_input_size` and `max_size` are in megabytes
+    pub fn new(dir: PathBuf, max_input_size: u64, max_size: u64) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        // temporary files can only be left over from a previous run
+
+        let mut size = 0;
+
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+
+            if is_temp(&entry.path()) {
+                let _ = std::fs::remove_file(entry.path());
+            } else {
+                size += entry.metadata()?.len();
+            }
+        }
+
+        Ok(Self {
+            dir,
+            max_input_size: 1024 * 1024 * max_input_size,
+            max_size: 1024 * 1024 * max_size,
+            size: Mutex::new(size),
+        })
+    }
+
+    fn path(&self, file_hash: &str, width: u32, height: u32, format: ThumbnailFormat) -> PathBuf {
+        self.dir.join(format!("{file_hash}_{width}x{height}.{}", format.extension()))
+    }
+
+    /// The hash gets used in file paths, so anything that doesn't look like one can't be cached
+    pub fn is_valid_hash(file_hash: &str) -> bool {
+        !file_hash.is_empty() && file_hash.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    pub async fn get(&self, file_hash: &str, width: u32, height: u32) -> Option<(Vec<u8>, ThumbnailFormat)> {
+        for format in ThumbnailFormat::ALL {
+            match tokio::fs::read(self.path(file_hash, width, height, format)).await {
+                Ok(data) => return Some((data, format)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => warn!("Could not read a cached thumbnail: {e}"),
+            }
+        }
+
+        None
+    }
+
+    /// Writes into a temporary file first, so that concurrent requests never read a half written thumbnail
+    pub async fn put(&self, file_hash: &str, width: u32, height: u32, format: ThumbnailFormat, data: &[u8]) -> Result<(), ResError> {
+        let temp_id: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+
+        let temp_path = self.dir.join(format!("{TEMP_PREFIX}{temp_id}"));
+
+        tokio::fs::write(&temp_path, data).await?;
+
+        if let Err(e) = tokio::fs::rename(&temp_path, self.path(file_hash, width, height, format)).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+
+        let size = {
+            let mut size = self.size.lock().unwrap();
+            *size += data.len() as u64;
+            *size
+        };
+
+        if self.max_size != 0 && size > self.max_size {
+            self.remove_oldest().await;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the oldest thumbnails until the cache is down to 90% of `max_size`, so that it doesn't have to be done on every `put`
+    async fn remove_oldest(&self) {
+        let Ok(mut entries) = tokio::fs::read_dir(&self.dir).await else {
+            return;
+        };
+
+        let mut thumbnails = Vec::new();
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+
+            if let (false, Ok(metadata)) = (is_temp(&path), entry.metadata().await) {
+                thumbnails.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), metadata.len(), path));
+            }
+        }
+
+        thumbnails.sort_unstable_by_key(|(modified, _, _)| *modified);
+
+        let mut size: u64 = thumbnails.iter().map(|(_, len, _)| len).sum();
+        let target = self.max_size / 10 * 9;
+
+        for (_, len, path) in thumbnails {
+            if size <= target {
+                break;
+            }
+
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => size -= len,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => size -= len,
+                Err(e) => warn!("Could not remove {}: {e}", path.display()),
+            }
+        }
+
+        debug!("Thumbnail cache has been cleaned up to {size} bytes");
+        *self.size.lock().unwrap() = size;
+    }
+}
+
+const TEMP_PREFIX: &str = "tmp-";
+
+fn is_temp(path: &std::path::Path) -> bool {
+    path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with(TEMP_PREFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn oldest_removed_over_limit() {
+        let dir = std::env::temp_dir().join(format!("thumbnail-cache-{}", thread_rng().gen::<u64>()));
+        let mut cache = ThumbnailCache::new(dir, 1, 0).unwrap();
+        cache.max_size = 1000;
+
+        let thumbnail = vec![0; 300];
+
+        for (i, hash) in ["a", "b", "c"].into_iter().enumerate() {
+            cache.put(hash, 32, 32, ThumbnailFormat::Jpeg, &thumbnail).await.unwrap();
+
+            let file = std::fs::File::options().write(true).open(cache.path(hash, 32, 32, ThumbnailFormat::Jpeg)).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(60 - i as u64)).unwrap();
+        }
+
+        cache.put("d", 32, 32, ThumbnailFormat::Jpeg, &thumbnail).await.unwrap();
+
+        assert!(cache.get("a", 32, 32).await.is_none());
+        assert!(cache.get("b", 32, 32).await.is_some());
+        assert!(cache.get("d", 32, 32).await.is_some());
+        assert_eq!(900, *cache.size.lock().unwrap());
+    }
+}
@@ -0,0 +1,182 @@
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, time::{Duration, Instant}};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::types::unix_now;
+
+pub type TokenHash = [u8; 32];
+
+/// In-memory cache of access token validation results, so that not every request has to go through the Auth service.
+/// Tokens are stored as hashes. Entries never outlive the tokens' own `exp`, so they can expire out of the insertion order,
+/// which is why the queue is only used for making room, and each lookup checks the expiry by itself
+#[derive(Debug)]
+pub struct TokenCache {
+    ttl: Duration,
+    max_size: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<TokenHash, (i32, Instant)>,
+    queue: VecDeque<(TokenHash, Instant)>,
+}
+
+impl TokenCache {
+    /// `ttl` is capped by `access_token_ttl`, so that entries never outlive the tokens themselves. A ttl or size of 0 disables the cache
+    pub fn new(ttl: u64, access_token_ttl: i64, max_size: usize) -> Self {
+        let ttl = ttl.min(access_token_ttl.max(0) as u64);
+
+        Self {
+            ttl: Duration::from_secs(ttl),
+            max_size,
+            inner: Default::default(),
+        }
+    }
+
+    fn is_disabled(&self) -> bool {
+        self.ttl.is_zero() || self.max_size == 0
+    }
+
+    /// Returns the user id that the token belongs to, if the token has been validated recently
+    pub fn get(&self, access_token: &str) -> Option<i32> {
+        if self.is_disabled() {
+            return None;
+        }
+
+        let inner = self.inner.lock().unwrap();
+
+        match inner.entries.get(&token_hash(access_token)) {
+            Some((user_id, expires_at)) if *expires_at > Instant::now() => Some(*user_id),
+            _ => None,
+        }
+    }
+
+    /// Caches the token until either the ttl passes or the token expires, whichever comes first.
+    /// Tokens without a readable `exp` claim don't get cached at all
+    pub fn insert(&self, access_token: &str, user_id: i32) {
+        if self.is_disabled() {
+            return;
+        }
+
+        let Some(expires_in) = token_expires_in(access_token) else {
+            return;
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        // getting rid of expired entries first, and then of the oldest ones if the cache is still full
+
+        if inner.entries.len() >= self.max_size {
+            inner.entries.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+
+        while let Some((_, expires_at)) = inner.queue.front() {
+            if *expires_at > now && inner.entries.len() < self.max_size {
+                break;
+            }
+
+            let (key, expires_at) = inner.queue.pop_front().unwrap();
+            if inner.entries.get(&key).is_some_and(|(_, e)| *e == expires_at) {
+                inner.entries.remove(&key);
+            }
+        }
+
+        let key = token_hash(access_token);
+        let expires_at = now + self.ttl.min(expires_in);
+
+        inner.entries.insert(key, (user_id, expires_at));
+        inner.queue.push_back((key, expires_at));
+    }
+
+    /// Removes the token from the cache, so that it stops working right away (e.g. on logout)
+    pub fn invalidate(&self, access_token: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = token_hash(access_token);
+
+        inner.entries.remove(&key);
+        inner.queue.retain(|(k, _)| *k != key);
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    exp: u64,
+}
+
+/// Reads the `exp` claim from the JWT's payload. The signature doesn't need to be verified, since the Auth service has
+/// already validated the token by the time it gets cached. Returns `None` if the token is already expired or isn't a JWT
+pub fn token_expires_in(access_token: &str) -> Option<Duration> {
+    let payload = access_token.split('.').nth(1)?;
+    let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+
+    match claims.exp.checked_sub(unix_now()) {
+        Some(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+        _ => None,
+    }
+}
+
+pub fn token_hash(access_token: &str) -> TokenHash {
+    Sha256::digest(access_token).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jwt(exp: u64) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(format!(r#"{{"user_id":1,"exp":{exp}}}"#));
+        format!("eyJhbGciOiJIUzI1NiJ9.{payload}.signature")
+    }
+
+    #[test]
+    fn entry_expires_with_token() {
+        let cache = TokenCache::new(30, 60, 10);
+        let token = jwt(unix_now() + 2);
+
+        cache.insert(&token, 1);
+        assert_eq!(cache.get(&token), Some(1));
+
+        let (_, expires_at) = cache.inner.lock().unwrap().entries[&token_hash(&token)];
+        assert!(expires_at <= Instant::now() + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn expired_or_opaque_token_not_cached() {
+        let cache = TokenCache::new(30, 60, 10);
+
+        cache.insert(&jwt(unix_now() - 1), 1);
+        cache.insert("not-a-jwt", 1);
+
+        assert!(cache.inner.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn invalidate_removes_queue_entry() {
+        let cache = TokenCache::new(30, 60, 10);
+        let token = jwt(unix_now() + 60);
+
+        cache.insert(&token, 1);
+        cache.invalidate(&token);
+
+        assert_eq!(cache.get(&token), None);
+        assert!(cache.inner.lock().unwrap().queue.is_empty());
+    }
+
+    #[test]
+    fn oldest_entry_evicted_when_full() {
+        let cache = TokenCache::new(30, 60, 2);
+        let tokens: Vec<_> = (0..3).map(|i| jwt(unix_now() + 60 + i)).collect();
+
+        for token in &tokens {
+            cache.insert(token, 1);
+        }
+
+        assert_eq!(cache.get(&tokens[0]), None);
+        assert_eq!(cache.get(&tokens[1]), Some(1));
+        assert_eq!(cache.get(&tokens[2]), Some(1));
+    }
+}
@@ -1,10 +1,13 @@
+use std::{sync::Arc, time::{Instant, SystemTime, UNIX_EPOCH}};
+
 use axum::extract::FromRequest;
 use axum::response::IntoResponse;
 use axum_extra::extract::cookie::{CookieJar, Cookie, SameSite};
-use axum::http::StatusCode;
+use axum::http::{header, HeaderMap, HeaderName, StatusCode};
 use futures_util::Future;
 use serde::{Deserialize, Serialize};
 use tonic::transport::Channel;
+use tonic_health::pb::health_client::HealthClient;
 use tracing::{debug, error};
 use utoipa::openapi::{ResponseBuilder, ResponsesBuilder};
 use utoipa::{IntoResponses, ToSchema};
@@ -12,6 +15,19 @@ use utoipa::{IntoResponses, ToSchema};
 use crate::proto::shelves::shelves_client::ShelvesClient;
 use crate::proto::{notes::notes_client::NotesClient, tags::tags_client::TagsClient, files::files_client::FilesClient, auth::auth_client::AuthClient};
 use crate::error::ResError;
+use crate::events::EventHub;
+use crate::idempotency::IdempotencyStore;
+use crate::telemetry;
+use crate::fingerprint::IpMode;
+use crate::refresh::RefreshGroup;
+use crate::routes::files::quota::QuotaTracker;
+use crate::shutdown::Shutdown;
+use crate::token_cache::TokenCache;
+use crate::links::LinkSigner;
+use crate::scanner::Scanner;
+use crate::thumbnail_cache::ThumbnailCache;
+use crate::upload_progress::ProgressHub;
+use crate::upload_store::UploadStore;
 
 pub type ServerResult<T> = Result<(StatusCode, Json<ResultBody<T>>), ResError>;
 pub type CookieResult = Result<(StatusCode, CookieJar, Json<ResultBody<()>>), ResError>;
@@ -23,6 +39,22 @@ pub struct AppState {
     pub frontend_url: String,
     pub req_body_limit: usize,
     pub file_chunk_size: usize,
+    pub upload_store: Arc<UploadStore>,
+    pub upload_progress: Arc<ProgressHub>,
+    pub events: Arc<EventHub>,
+    pub idempotency: Arc<IdempotencyStore>,
+    pub scanner: Arc<Scanner>,
+    /// Max total size (in megabytes) of a single user's files. 0 means no limit
+    pub user_storage_quota: u64,
+    /// Max amount of a single user's files. 0 means no limit
+    pub user_file_quota: u64,
+    pub quota_tracker: Arc<QuotaTracker>,
+    /// Url that the public links start with
+    pub public_url: String,
+    pub link_signer: Arc<LinkSigner>,
+    /// Max lifetime (in seconds) of a public link
+    pub max_link_ttl: u64,
+    pub thumbnail_cache: Arc<ThumbnailCache>,
 
     pub auth_token: String,
     pub data_token: String,
@@ -31,12 +63,54 @@ pub struct AppState {
     pub refresh_token_ttl: i64,
     pub access_token_key: String,
     pub refresh_token_key: String,
+    pub cookie_config: CookieConfig,
+    pub csrf_cookie_key: String,
+    pub csrf_header: HeaderName,
+    pub token_precedence: TokenSource,
+    pub auto_refresh: bool,
+    pub refresh_group: Arc<RefreshGroup>,
+    pub token_cache: Arc<TokenCache>,
+
+    pub fingerprint_header: HeaderName,
+    pub fingerprint_ip_mode: IpMode,
 
     pub auth_client: AuthClient<Channel>,
     pub notes_client: NotesClient<Channel>,
     pub tags_client: TagsClient<Channel>,
     pub files_client: FilesClient<Channel>,
     pub shelves_client: ShelvesClient<Channel>,
+    pub auth_health_client: HealthClient<Channel>,
+    pub data_health_client: HealthClient<Channel>,
+    /// Timeout (in milliseconds) for each dependency check in `/readyz`
+    pub readiness_timeout: u64,
+    pub shutdown: Arc<Shutdown>,
+    /// How long (in seconds) to wait for in-flight requests to finish on shutdown
+    pub shutdown_grace_period: u64,
+    /// Port for the `/metrics` route. If `None`, the route is served on the main port
+    pub metrics_port: Option<u16>,
+}
+
+/// Access token that the request has been authenticated with. If it has been refreshed along the way, this is the new one
+#[derive(Clone, Debug)]
+pub struct AccessToken(pub String);
+
+/// Where a token was taken from. Also used to define which source gets checked first when the request has both
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenSource {
+    Cookie,
+    Bearer,
+}
+
+impl std::str::FromStr for TokenSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cookie" => Ok(Self::Cookie),
+            "bearer" => Ok(Self::Bearer),
+            _ => Err(anyhow::anyhow!("Invalid token source: {s}, expected either cookie or bearer")),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
@@ -103,11 +177,12 @@ impl<T: Serialize> IntoResponse for Json<T> {
     }
 }
 
-/// Generically calls a grpc service
+/// Generically calls a grpc service. The `method` is only used as a label for the call's metrics
 pub async fn call_grpc_service<ReqBody, ReqFn, ResBody, ResFuture>(
     body: ReqBody,
     req_fn: ReqFn,
     service_token: &str,
+    method: &'static str,
 ) -> Result<ResBody, tonic::Status>
 where
     ReqFn: FnOnce(tonic::Request<ReqBody>) -> ResFuture,
@@ -116,27 +191,120 @@ where
     let mut request = tonic::Request::new(body);
     let header_value = format!("Bearer {}", service_token).parse().unwrap();
     request.metadata_mut().append("authorization", header_value);
-    let response = req_fn(request).await?;
-    Ok(response.into_inner())
+
+    let start = Instant::now();
+    let response = req_fn(request).await;
+
+    let code = match &response {
+        Ok(_) => tonic::Code::Ok,
+        Err(status) => status.code(),
+    };
+    telemetry::record_grpc_call(method, code, start.elapsed());
+
+    Ok(response?.into_inner())
+}
+
+/// Gets a token either from the cookie jar or from the `Authorization: Bearer` header, checking the sources in the order defined by `precedence`
+pub fn get_token<'a>(
+    jar: &'a CookieJar,
+    headers: &'a HeaderMap,
+    cookie_key: &str,
+    precedence: TokenSource,
+) -> Option<(&'a str, TokenSource)> {
+    let from_cookie = || jar
+        .get(cookie_key)
+        .map(|c| (c.value(), TokenSource::Cookie));
+
+    let from_header = || headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| (v.trim(), TokenSource::Bearer));
+
+    match precedence {
+        TokenSource::Cookie => from_cookie().or_else(from_header),
+        TokenSource::Bearer => from_header().or_else(from_cookie),
+    }
+}
+
+/// Attributes that the auth cookies get created with
+#[derive(Clone, Debug)]
+pub struct CookieConfig {
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+    /// Path for the access cookie
+    pub path: String,
+    /// Path for the refresh cookie, so that it doesn't get sent with every request
+    pub refresh_path: String,
+    pub host_prefix: bool,
+}
+
+impl CookieConfig {
+    /// Prefixes the cookie key with `__Host-` if prefixes are enabled. Since `__Host-` cookies are only allowed on the `/` path,
+    /// cookies with other paths get the `__Secure-` prefix instead
+    pub fn prefixed_key(&self, key: &str, path: &str) -> String {
+        match (self.host_prefix, path) {
+            (false, _) => key.into(),
+            (true, "/") => format!("__Host-{key}"),
+            (true, _) => format!("__Secure-{key}"),
+        }
+    }
+}
+
+/// Whether a browser sends a cookie with the `cookie_path` along with a request to the `request_path`, as defined in RFC 6265
+pub fn cookie_path_matches(cookie_path: &str, request_path: &str) -> bool {
+    match request_path.strip_prefix(cookie_path) {
+        Some(rest) => rest.is_empty() || cookie_path.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+pub fn parse_same_site(value: &str) -> anyhow::Result<SameSite> {
+    match value {
+        "strict" => Ok(SameSite::Strict),
+        "lax" => Ok(SameSite::Lax),
+        "none" => Ok(SameSite::None),
+        _ => Err(anyhow::anyhow!("Invalid SameSite value: {value}, expected one of: strict, lax, none")),
+    }
 }
 
 pub trait CreateAndAddCookie {
-    fn add_new_cookie(self, _: String,  _: String, _: i64) -> Self;
+    fn add_new_cookie(self, _: String,  _: String, _: i64, _: &str, _: &CookieConfig) -> Self;
+    fn add_new_csrf_cookie(self, _: String, _: String, _: i64, _: &CookieConfig) -> Self;
 }
 impl CreateAndAddCookie for CookieJar {
     /// Creates a new cookie based on the arguments and adds it to the jar
-    fn add_new_cookie(self, cookie_key: String, token: String, token_exp: i64) -> Self {
-        let exp_time = time::Duration::seconds(token_exp);
+    fn add_new_cookie(self, cookie_key: String, token: String, token_exp: i64, path: &str, config: &CookieConfig) -> Self {
+        self.add(build_cookie(cookie_key, token, token_exp, path, config, true))
+    }
+
+    /// Same as `add_new_cookie`, except that the cookie is readable from js, since the client has to copy its value into the csrf header
+    fn add_new_csrf_cookie(self, cookie_key: String, token: String, token_exp: i64, config: &CookieConfig) -> Self {
+        self.add(build_cookie(cookie_key, token, token_exp, &config.path, config, false))
+    }
+}
 
-        let cookie = Cookie::build((cookie_key, token))
-            .max_age(exp_time)
-            .path("/")
-            .same_site(SameSite::None)
-            .http_only(true)
-            .secure(false);
+fn build_cookie(cookie_key: String, token: String, token_exp: i64, path: &str, config: &CookieConfig, http_only: bool) -> Cookie<'static> {
+    let exp_time = time::Duration::seconds(token_exp);
 
-        self.add(cookie)
+    let mut cookie = Cookie::build((cookie_key, token))
+        .max_age(exp_time)
+        .path(path.to_string())
+        .same_site(config.same_site)
+        .http_only(http_only)
+        .secure(config.secure);
+
+    if let Some(domain) = &config.domain {
+        cookie = cookie.domain(domain.clone());
     }
+
+    cookie.build()
+}
+
+/// Current unix timestamp (in seconds)
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
 }
 
 pub fn new_ok_res<T>(code: StatusCode, data: T) -> ServerResult<T> {
@@ -156,6 +324,11 @@ pub fn new_cookie_ok_res(jar: CookieJar) -> CookieResult {
 
 /// Converts the arguments into a tuple that implements IntoResponse. It also logs the error messages that it receives
 pub fn new_err_res(status_code: StatusCode, response_msg: &str, internal_msg: String) -> (StatusCode, Json<ResultBody<()>>) {
+    new_err_data_res(status_code, response_msg, internal_msg, None)
+}
+
+/// Same as `new_err_res`, but with the `data` field, for the errors that come with something the client needs to recover
+pub fn new_err_data_res<T>(status_code: StatusCode, response_msg: &str, internal_msg: String, data: Option<T>) -> (StatusCode, Json<ResultBody<T>>) {
     let code = status_code.as_u16();
     let status = status_code.canonical_reason();
 
@@ -165,5 +338,22 @@ pub fn new_err_res(status_code: StatusCode, response_msg: &str, internal_msg: St
         debug!(code, status, response_msg, internal_msg);
     }
 
-    (status_code, Json(ResultBody { success: false, error: Some(response_msg.into()), data: None }))
+    (status_code, Json(ResultBody { success: false, error: Some(response_msg.into()), data }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cookie_path_matches;
+
+    #[test]
+    fn cookie_path_matching() {
+        assert!(cookie_path_matches("/", "/tags"));
+        assert!(cookie_path_matches("/access", "/access"));
+        assert!(cookie_path_matches("/files/", "/files/tus"));
+        assert!(cookie_path_matches("/files", "/files/tus"));
+
+        assert!(!cookie_path_matches("/access", "/tags"));
+        assert!(!cookie_path_matches("/access", "/accessories"));
+        assert!(!cookie_path_matches("/files/tus", "/files"));
+    }
 }
@@ -0,0 +1,244 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use serde::Serialize;
+use tokio::sync::watch;
+use utoipa::ToSchema;
+
+use crate::error::ResError;
+use crate::proto::files::File;
+
+/// How long the result of a finished upload stays available for the clients that subscribe late
+const KEEP_FINISHED: Duration = Duration::from_secs(60);
+/// How long a subscription waits for its upload to start
+const KEEP_PENDING: Duration = Duration::from_secs(600);
+/// How many subscriptions a single user can have waiting for their uploads to start
+const MAX_PENDING_PER_USER: usize = 32;
+
+/// Current state of a multipart upload. Each update replaces the previous one, so a client that subscribes late still gets the whole picture
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct UploadProgress {
+    /// Files in the same order as they are in the multipart body. Files that had to be saved on the gateway's side first
+    /// show up with 0 `chunks` until the rest of the body has been received
+    pub files: Vec<FileProgress>,
+    /// Whether the upload has finished, in which case this is the last update
+    pub done: bool,
+    /// Why the upload has failed as a whole. The details are in the upload's response
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct FileProgress {
+    pub name: String,
+    pub file_size: u64,
+    /// How many chunks have been forwarded to the Data service
+    pub chunks: u64,
+    /// How many bytes have been forwarded to the Data service. The last chunk is held back until the data has been verified,
+    /// so this only reaches `file_size` once the file is about to be created
+    pub bytes_forwarded: u64,
+    /// The new file, once it has been uploaded
+    pub file: Option<File>,
+    pub error: Option<String>,
+}
+
+/// User id and upload id, since the upload ids are only unique for a single user
+type UploadKey = (i32, String);
+
+#[derive(Debug)]
+struct Entry {
+    tx: watch::Sender<UploadProgress>,
+    started: bool,
+    /// When the entry can be removed, unless its upload is still going
+    expires: Instant,
+}
+
+/// Lets clients follow their uploads. Uploads are identified by the ids that the clients choose,
+/// which makes it possible to subscribe before the upload request is sent
+#[derive(Debug, Default)]
+pub struct ProgressHub {
+    uploads: Mutex<HashMap<UploadKey, Entry>>,
+}
+
+fn check_upload_id(upload_id: &str) -> Result<(), ResError> {
+    match !upload_id.is_empty() && upload_id.len() <= 64 && upload_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        true => Ok(()),
+        false => Err(ResError::InvalidFields(format!("Received an invalid upload id: {upload_id}"))),
+    }
+}
+
+impl ProgressHub {
+    fn remove_expired(uploads: &mut HashMap<UploadKey, Entry>) {
+        let now = Instant::now();
+        uploads.retain(|_, entry| (entry.started && !entry.tx.borrow().done) || entry.expires > now);
+    }
+
+    pub fn subscribe(&self, user_id: i32, upload_id: &str) -> Result<watch::Receiver<UploadProgress>, ResError> {
+        check_upload_id(upload_id)?;
+
+        let mut uploads = self.uploads.lock().unwrap();
+        Self::remove_expired(&mut uploads);
+
+        let key = (user_id, upload_id.to_string());
+
+        if !uploads.contains_key(&key) {
+            let pending = uploads.iter().filter(|((id, _), entry)| *id == user_id && !entry.started).count();
+
+            if pending >= MAX_PENDING_PER_USER {
+                return Err(ResError::BadRequest(format!("The user is already waiting for {pending} uploads to start")));
+            }
+        }
+
+        let entry = uploads.entry(key).or_insert_with(|| Entry {
+            tx: watch::channel(UploadProgress::default()).0,
+            started: false,
+            expires: Instant::now() + KEEP_PENDING,
+        });
+
+        Ok(entry.tx.subscribe())
+    }
+
+    /// Starts reporting the progress of an upload. Fails if another upload with the same id is still going
+    pub fn start(self: &Arc<Self>, user_id: i32, upload_id: String) -> Result<ProgressReporter, ResError> {
+        check_upload_id(&upload_id)?;
+
+        let mut uploads = self.uploads.lock().unwrap();
+        Self::remove_expired(&mut uploads);
+
+        let key = (user_id, upload_id);
+
+        let tx = match uploads.get_mut(&key) {
+            Some(entry) if entry.started && !entry.tx.borrow().done => {
+                return Err(ResError::Conflict(format!("Upload {} is already in progress", key.1)));
+            },
+            Some(entry) => {
+                entry.started = true;
+                entry.tx.send_replace(UploadProgress::default());
+                entry.tx.clone()
+            },
+            None => {
+                let tx = watch::channel(UploadProgress::default()).0;
+                uploads.insert(key.clone(), Entry { tx: tx.clone(), started: true, expires: Instant::now() });
+                tx
+            },
+        };
+
+        Ok(ProgressReporter { inner: Some((self.clone(), key, tx)) })
+    }
+
+    fn finished(&self, key: &UploadKey) {
+        if let Some(entry) = self.uploads.lock().unwrap().get_mut(key) {
+            entry.expires = Instant::now() + KEEP_FINISHED;
+        }
+    }
+}
+
+/// Reports the progress of a single upload. If it gets dropped before `finish`, the upload is reported as failed
+#[derive(Debug)]
+pub struct ProgressReporter {
+    inner: Option<(Arc<ProgressHub>, UploadKey, watch::Sender<UploadProgress>)>,
+}
+
+impl ProgressReporter {
+    /// Reporter for the uploads that nobody is following
+    pub fn none() -> Self {
+        Self { inner: None }
+    }
+
+    fn update(&self, modify: impl FnOnce(&mut UploadProgress)) {
+        if let Some((_, _, tx)) = &self.inner {
+            tx.send_modify(modify);
+        }
+    }
+
+    fn update_file(&self, index: usize, modify: impl FnOnce(&mut FileProgress)) {
+        self.update(|progress| {
+            if progress.files.len() <= index {
+                progress.files.resize_with(index + 1, Default::default);
+            }
+
+            modify(&mut progress.files[index]);
+        });
+    }
+
+    pub fn start_file(&self, index: usize, name: &str, file_size: u64) {
+        self.update_file(index, |file| {
+            file.name = name.to_string();
+            file.file_size = file_size;
+        });
+    }
+
+    pub fn chunk_sent(&self, index: usize, bytes: usize) {
+        self.update_file(index, |file| {
+            file.chunks += 1;
+            file.bytes_forwarded += bytes as u64;
+        });
+    }
+
+    pub fn finish_file(&self, index: usize, result: Option<&File>, error: Option<&str>) {
+        self.update_file(index, |file| {
+            file.file = result.cloned();
+            file.error = error.map(String::from);
+        });
+    }
+
+    pub fn finish(mut self, error: Option<String>) {
+        if let Some((hub, key, tx)) = self.inner.take() {
+            tx.send_modify(|progress| {
+                progress.done = true;
+                progress.error = error;
+            });
+
+            hub.finished(&key);
+        }
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            ProgressReporter { inner: self.inner.take() }.finish(Some("The upload has failed".into()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_subscriptions_capped_per_user() {
+        let hub = Arc::new(ProgressHub::default());
+
+        for i in 0..MAX_PENDING_PER_USER {
+            hub.subscribe(1, &format!("upload{i}")).unwrap();
+        }
+
+        assert!(hub.subscribe(1, "one_too_many").is_err());
+
+        // subscribing to the same upload again and other users are fine
+        assert!(hub.subscribe(1, "upload0").is_ok());
+        assert!(hub.subscribe(2, "upload0").is_ok());
+
+        // started uploads don't count as pending anymore
+        let _reporter = hub.start(1, "upload0".into()).unwrap();
+        assert!(hub.subscribe(1, "one_too_many").is_ok());
+    }
+
+    #[test]
+    fn forwarded_bytes_reported() {
+        let hub = Arc::new(ProgressHub::default());
+        let rx = hub.subscribe(1, "upload").unwrap();
+        let reporter = hub.start(1, "upload".into()).unwrap();
+
+        reporter.start_file(0, "test.txt", 10);
+        reporter.chunk_sent(0, 4);
+        reporter.chunk_sent(0, 3);
+
+        let progress = rx.borrow().clone();
+        assert_eq!(2, progress.files[0].chunks);
+        assert_eq!(7, progress.files[0].bytes_forwarded);
+        assert!(!progress.done);
+
+        reporter.finish(None);
+        assert!(rx.borrow().done);
+    }
+}
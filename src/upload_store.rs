@@ -0,0 +1,362 @@
+use std::{collections::{hash_map, HashMap, HashSet}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::error::ResError;
+use crate::types::unix_now;
+
+const TEMP_PREFIX: &str = "tmp-";
+
+/// Local disk storage for resumable uploads. Each upload consists of two files in `dir`:
+/// `{id}.json` with the upload's info, and `{id}.bin` with the data received so far.
+/// The size of the data file is the upload's offset, so the progress survives restarts, and its modification time is when the upload was last worked on.
+/// Multipart uploads also use the directory for temporary files, named `tmp-{id}.bin`
+#[derive(Debug)]
+pub struct UploadStore {
+    dir: PathBuf,
+    /// Max size of a single upload (in bytes)
+    pub max_size: u64,
+    /// How long an unfinished upload is kept for since it was last worked on
+    pub expiration: Duration,
+    locked: Mutex<HashSet<String>>,
+    staged: Mutex<Staged>,
+}
+
+/// Length of each upload along with the totals of each user, so that the quota checks don't have to go through the directory
+#[derive(Debug, Default)]
+struct Staged {
+    uploads: HashMap<String, (i32, u64)>,
+    /// Total length and amount of each user's uploads
+    totals: HashMap<i32, (u64, u64)>,
+}
+
+impl Staged {
+    fn add(&mut self, id: String, user_id: i32, length: u64) {
+        if self.uploads.insert(id, (user_id, length)).is_none() {
+            let (bytes, files) = self.totals.entry(user_id).or_default();
+            *bytes += length;
+            *files += 1;
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        let Some((user_id, length)) = self.uploads.remove(id) else {
+            return;
+        };
+
+        if let hash_map::Entry::Occupied(mut entry) = self.totals.entry(user_id) {
+            let (bytes, files) = entry.get_mut();
+            *bytes = bytes.saturating_sub(length);
+            *files = files.saturating_sub(1);
+
+            if *files == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadInfo {
+    pub user_id: i32,
+    pub name: String,
+    pub note_id: Option<i32>,
+    pub shelf_id: Option<i32>,
+    pub length: u64,
+    /// Unix timestamp (in seconds)
+    pub created: u64,
+}
+
+#[derive(Debug)]
+pub struct Upload {
+    pub info: UploadInfo,
+    pub offset: u64,
+    pub expires: SystemTime,
+}
+
+/// File in the upload directory that gets removed once dropped
+#[derive(Debug)]
+pub struct TempFile {
+    pub path: PathBuf,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Could not remove {}: {e}", self.path.display());
+            }
+        }
+    }
+}
+
+/// Makes sure that only one request is working with an upload at a time. The upload gets unlocked once dropped
+#[derive(Debug)]
+pub struct UploadLock {
+    store: Arc<UploadStore>,
+    id: String,
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        self.store.locked.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl UploadStore {
+    /// `max_size` is in megabytes and `expiration` is in seconds
+    pub fn new(dir: PathBuf, max_size: u64, expiration: u64) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        // the uploads from before a restart still count toward their users' quotas.
+        // the temporary files can only be left over if the service didn't get to remove them, since nothing is using them yet
+
+        let mut staged = Staged::default();
+
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+
+            if id.starts_with(TEMP_PREFIX) {
+                debug!("Removing leftover temporary file {}", path.display());
+
+                if let Err(e) = std::fs::remove_file(&path) {
+                    warn!("Could not remove {}: {e}", path.display());
+                }
+            } else if path.extension().is_some_and(|e| e == "json") {
+                let info: Option<UploadInfo> = std::fs::read(&path).ok()
+                    .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+
+                if let Some(info) = info {
+                    staged.add(id.to_string(), info.user_id, info.length);
+                }
+            }
+        }
+
+        Ok(Self {
+            dir,
+            max_size: 1024 * 1024 * max_size,
+            expiration: Duration::from_secs(expiration),
+            locked: Default::default(),
+            staged: Mutex::new(staged),
+        })
+    }
+
+    pub fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.bin"))
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    /// Reserves a path for a temporary file. The file itself has to be created by the caller
+    pub fn temp_file(&self) -> TempFile {
+        TempFile { path: self.dir.join(format!("{TEMP_PREFIX}{}.bin", new_id())) }
+    }
+
+    /// When an upload that was last worked on at `last_activity` expires
+    pub fn expires_at(&self, last_activity: SystemTime) -> SystemTime {
+        last_activity + self.expiration
+    }
+
+    pub async fn create(&self, user_id: i32, name: String, note_id: Option<i32>, shelf_id: Option<i32>, length: u64) -> Result<String, ResError> {
+        self.remove_expired().await;
+
+        let id = new_id();
+
+        let created = unix_now();
+        let info = UploadInfo { user_id, name, note_id, shelf_id, length, created };
+
+        tokio::fs::write(self.data_path(&id), []).await?;
+        tokio::fs::write(self.info_path(&id), serde_json::to_vec(&info).map_err(|e| ResError::ServerError(e.to_string()))?).await?;
+        self.staged.lock().unwrap().add(id.clone(), user_id, length);
+
+        Ok(id)
+    }
+
+    /// Returns the upload's info along with its current offset and expiry. Uploads of other users are treated as nonexistent
+    pub async fn get(&self, id: &str, user_id: i32) -> Result<Upload, ResError> {
+        let not_found = || ResError::NotFound(format!("Could not find upload {id}"));
+
+        // the id gets used in file paths
+
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(not_found());
+        }
+
+        let info: UploadInfo = match tokio::fs::read(self.info_path(id)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| ResError::ServerError(e.to_string()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_found()),
+            Err(e) => return Err(e.into()),
+        };
+
+        if info.user_id != user_id {
+            return Err(not_found());
+        }
+
+        let data = match tokio::fs::metadata(self.data_path(id)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_found()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let expires = self.expires_at(data.modified()?);
+
+        if expires < SystemTime::now() {
+            self.remove(id).await;
+            return Err(not_found());
+        }
+
+        Ok(Upload { info, offset: data.len(), expires })
+    }
+
+    pub fn lock(self: &Arc<Self>, id: &str) -> Result<UploadLock, ResError> {
+        match self.locked.lock().unwrap().insert(id.to_string()) {
+            true => Ok(UploadLock { store: self.clone(), id: id.to_string() }),
+            false => Err(ResError::Conflict(format!("Upload {id} is already being worked on by another request"))),
+        }
+    }
+
+    pub async fn remove(&self, id: &str) {
+        self.staged.lock().unwrap().remove(id);
+
+        for path in [self.info_path(id), self.data_path(id)] {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Could not remove {}: {e}", path.display());
+                }
+            }
+        }
+    }
+
+    /// Total length and amount of the user's uploads, so that they count toward the user's quota before they become files.
+    /// `exclude` is left out, so that an upload can be checked against the quota once more before becoming a file
+    pub fn staged_usage(&self, user_id: i32, exclude: Option<&str>) -> (u64, u64) {
+        let staged = self.staged.lock().unwrap();
+        let (mut bytes, mut files) = staged.totals.get(&user_id).copied().unwrap_or_default();
+
+        if let Some(&(owner, length)) = exclude.and_then(|id| staged.uploads.get(id)) {
+            if owner == user_id {
+                bytes = bytes.saturating_sub(length);
+                files = files.saturating_sub(1);
+            }
+        }
+
+        (bytes, files)
+    }
+
+    /// Removes the expired uploads that haven't been touched since expiring, along with the temporary files
+    /// that haven't been written to for as long, in case they couldn't be removed after their requests
+    async fn remove_expired(&self) {
+        let Ok(mut entries) = tokio::fs::read_dir(&self.dir).await else {
+            return;
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+
+            if id.starts_with(TEMP_PREFIX) {
+                if self.is_expired(&[&path]).await {
+                    debug!("Removing stale temporary file {}", path.display());
+
+                    if let Err(e) = tokio::fs::remove_file(&path).await {
+                        warn!("Could not remove {}: {e}", path.display());
+                    }
+                }
+            } else if path.extension().is_some_and(|e| e == "json") && !self.locked.lock().unwrap().contains(id) {
+                // an upload without its data file goes by when it was created
+
+                if self.is_expired(&[&self.data_path(id), &path]).await {
+                    debug!("Removing expired upload {id}");
+                    self.remove(id).await;
+                }
+            }
+        }
+    }
+
+    /// Whether the first of `paths` that exists hasn't been modified for longer than the expiration
+    async fn is_expired(&self, paths: &[&Path]) -> bool {
+        for path in paths {
+            if let Ok(modified) = tokio::fs::metadata(path).await.and_then(|m| m.modified()) {
+                return self.expires_at(modified) < SystemTime::now();
+            }
+        }
+
+        false
+    }
+}
+
+fn new_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_store(expiration: u64) -> UploadStore {
+        let dir = std::env::temp_dir().join(format!("upload-store-{}", new_id()));
+        UploadStore::new(dir, 1, expiration).unwrap()
+    }
+
+    fn set_modified(path: &Path, ago: Duration) {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - ago).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expiry_follows_last_write() {
+        let store = test_store(60);
+        let id = store.create(1, "a.txt".into(), Some(1), None, 10).await.unwrap();
+
+        set_modified(&store.info_path(&id), Duration::from_secs(3600));
+        let upload = store.get(&id, 1).await.unwrap();
+        assert!(upload.expires > SystemTime::now() + Duration::from_secs(50));
+
+        set_modified(&store.data_path(&id), Duration::from_secs(120));
+        assert!(store.get(&id, 1).await.is_err());
+        assert_eq!((0, 0), store.staged_usage(1, None));
+    }
+
+    #[tokio::test]
+    async fn stale_temp_files_removed() {
+        let store = test_store(60);
+
+        let stale = store.temp_file();
+        std::fs::write(&stale.path, "stale").unwrap();
+        set_modified(&stale.path, Duration::from_secs(120));
+
+        let fresh = store.temp_file();
+        std::fs::write(&fresh.path, "fresh").unwrap();
+
+        store.create(1, "a.txt".into(), Some(1), None, 10).await.unwrap();
+
+        assert!(!stale.path.exists());
+        assert!(fresh.path.exists());
+    }
+
+    #[test]
+    fn leftover_temp_files_removed_on_start() {
+        let store = test_store(60);
+
+        let leftover = store.temp_file();
+        std::fs::write(&leftover.path, "leftover").unwrap();
+
+        UploadStore::new(store.dir.clone(), 1, 60).unwrap();
+        assert!(!leftover.path.exists());
+    }
+}